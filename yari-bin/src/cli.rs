@@ -1,16 +1,16 @@
 use clap::Parser;
 use clap_verbosity_flag::Verbosity;
-use std::{net::SocketAddr, path::PathBuf};
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf};
 use yari::{
     persistence,
-    rpc::{ClientRequest, RaftClient},
+    rpc::{MemberRole, MembersResponse, Membership, RaftClient, ReadConsistency},
     server,
     state_machine::StateMachine,
+    trillium_http::Status,
     url::Url,
-    Config, EphemeralState, ServerHandle,
+    Config, EphemeralState, Error, ServerHandle, YariClient,
 };
 
-#[allow(dead_code)]
 #[derive(Debug, Parser)]
 struct ClientOptions {
     #[arg(short, long, default_value = "10")]
@@ -60,6 +60,10 @@ enum Command {
         client_options: ClientOptions,
         #[command(flatten)]
         verbosity: Verbosity,
+
+        /// join as a non-voting learner
+        #[arg(long)]
+        learner: bool,
     },
 
     Resume {
@@ -103,11 +107,55 @@ enum Command {
         verbosity: Verbosity,
     },
 
+    AddLearner {
+        #[command(flatten)]
+        client_options: ClientOptions,
+
+        url: Url,
+
+        #[command(flatten)]
+        verbosity: Verbosity,
+    },
+
+    Promote {
+        #[command(flatten)]
+        client_options: ClientOptions,
+
+        url: Url,
+
+        #[command(flatten)]
+        verbosity: Verbosity,
+    },
+
+    TransferLeader {
+        #[command(flatten)]
+        client_options: ClientOptions,
+
+        url: Url,
+
+        #[command(flatten)]
+        verbosity: Verbosity,
+    },
+
+    /// list the cluster's members and configurations
+    Members {
+        #[command(flatten)]
+        client_options: ClientOptions,
+
+        #[command(flatten)]
+        verbosity: Verbosity,
+    },
+
     Client {
         #[command(flatten)]
         client_options: ClientOptions,
         #[command(flatten)]
         verbosity: Verbosity,
+
+        /// linearizable, lease, or stale
+        #[arg(long, default_value = "linearizable")]
+        read_consistency: ReadConsistency,
+
         ext: Vec<String>,
     },
 }
@@ -122,6 +170,10 @@ impl Command {
             Command::Ping { verbosity, .. } => verbosity,
             Command::Add { verbosity, .. } => verbosity,
             Command::Remove { verbosity, .. } => verbosity,
+            Command::AddLearner { verbosity, .. } => verbosity,
+            Command::Promote { verbosity, .. } => verbosity,
+            Command::TransferLeader { verbosity, .. } => verbosity,
+            Command::Members { verbosity, .. } => verbosity,
             Command::Client { verbosity, .. } => verbosity,
         }
     }
@@ -140,15 +192,18 @@ async fn exec<S: StateMachine>(state_machine: S, command: Command) {
         Command::Inspect { server_options, .. } => {
             let statefile_path = extract_statefile_path(&server_options);
             if statefile_path.exists() {
-                let mut raft_state = persistence::load_or_default(EphemeralState {
+                let eph = EphemeralState {
                     id: server_options.url.to_string(),
-                    statefile_path,
                     config: config_from_options(&server_options),
                     state_machine,
-                })
-                .await;
+                };
+                let mut raft_state = persistence::open(eph, &statefile_path)
+                    .await
+                    .unwrap_or_else(|e| {
+                        panic!("could not load {}: {e}", statefile_path.to_str().unwrap())
+                    });
 
-                raft_state.commit().await;
+                raft_state.commit().await.unwrap();
 
                 println!("{raft_state:#?}");
             } else {
@@ -176,6 +231,7 @@ async fn exec<S: StateMachine>(state_machine: S, command: Command) {
         Command::Join {
             client_options,
             server_options,
+            learner,
             ..
         } => {
             let statefile = extract_statefile_path(&server_options);
@@ -189,16 +245,23 @@ async fn exec<S: StateMachine>(state_machine: S, command: Command) {
                 let handle = start_server(&server_options, false, state_machine).await;
                 handle.info().await;
                 for server in &servers {
-                    if raft_client
-                        .add(server, server_options.url.as_str())
-                        .await
-                        .is_ok()
-                    {
-                        println!("added to {}", server.as_str());
-                        handle.await;
-                        return;
+                    let id = server_options.url.as_str();
+                    let added = if learner {
+                        raft_client.add_learner(server, id).await
                     } else {
-                        println!("could not reach {}", server.as_str());
+                        raft_client.add(server, id).await
+                    };
+
+                    match added {
+                        Ok(()) => {
+                            println!("added to {}", server.as_str());
+                            handle.await;
+                            return;
+                        }
+                        Err(e @ Error::ErrorResponse(..)) => {
+                            println!("could not join through {}: {e}", server.as_str());
+                        }
+                        Err(_) => println!("could not reach {}", server.as_str()),
                     }
                 }
 
@@ -240,9 +303,15 @@ async fn exec<S: StateMachine>(state_machine: S, command: Command) {
         } => {
             let servers = client_options.servers;
             for server in &servers {
-                if raft_client.add(server, url.as_str()).await.is_ok() {
-                    println!("added ({server})");
-                    return;
+                match raft_client.add(server, url.as_str()).await {
+                    Ok(()) => {
+                        println!("added ({server})");
+                        return;
+                    }
+                    Err(e @ Error::ErrorResponse(..)) => {
+                        println!("could not add through {server}: {e}");
+                    }
+                    Err(_) => {}
                 }
             }
 
@@ -265,33 +334,109 @@ async fn exec<S: StateMachine>(state_machine: S, command: Command) {
             panic!("no server of {servers:?} responded");
         }
 
-        Command::Client {
-            ext,
+        Command::AddLearner {
+            url,
             client_options,
             ..
-        } => match state_machine.cli(ext) {
-            Ok(Some(message)) => {
-                dbg!(&message);
-                for server in client_options.servers {
-                    if let Ok(result) = raft_client
-                        .client_append(
-                            &server,
-                            &ClientRequest {
-                                message: message.clone(),
-                            },
-                        )
-                        .await
-                    {
-                        println!("{:?}", result.result);
+        } => {
+            let servers = client_options.servers;
+            for server in &servers {
+                if raft_client.add_learner(server, url.as_str()).await.is_ok() {
+                    println!("added learner ({server})");
+                    return;
+                }
+            }
+
+            panic!("no server of {servers:?} responded");
+        }
+
+        Command::Promote {
+            url,
+            client_options,
+            ..
+        } => {
+            let servers = client_options.servers;
+            for server in &servers {
+                if raft_client.promote(server, url.as_str()).await.is_ok() {
+                    println!("promoted ({server})");
+                    return;
+                }
+            }
+
+            panic!("no server of {servers:?} promoted {url}");
+        }
+
+        Command::TransferLeader {
+            url,
+            client_options,
+            ..
+        } => {
+            let servers = client_options.servers;
+            for server in &servers {
+                match raft_client.transfer_leader(server, url.as_str()).await {
+                    Ok(()) => {
+                        println!("transferred leadership to {url} ({server})");
                         return;
                     }
+
+                    // the leader refused, so no other server will do better
+                    Err(e @ Error::ErrorResponse(status, _))
+                        if status != Status::ServiceUnavailable =>
+                    {
+                        panic!("could not transfer leadership to {url}: {e}")
+                    }
+
+                    Err(_) => {}
                 }
             }
-            Ok(None) => {}
-            Err(s) => {
-                panic!("{}", &s);
+
+            panic!("no server of {servers:?} transferred leadership to {url}");
+        }
+
+        Command::Members { client_options, .. } => {
+            let servers = client_options.servers;
+            for server in &servers {
+                if let Ok(members) = raft_client.members(server).await {
+                    print_members(&members);
+                    return;
+                }
             }
-        },
+
+            panic!("no server of {servers:?} responded");
+        }
+
+        Command::Client {
+            ext,
+            client_options,
+            read_consistency,
+            ..
+        } => {
+            let client = YariClient::<S>::new(client_options.servers)
+                .with_retries(client_options.retries)
+                .with_follow_redirects(!client_options.no_follow);
+
+            if let Ok(Some(query)) = state_machine.cli_query(ext.clone()) {
+                match client.query(query, read_consistency).await {
+                    Ok(response) => println!("{:?}", response.result),
+                    Err(e) => println!("could not query: {e}"),
+                }
+                return;
+            }
+
+            match state_machine.cli(ext) {
+                Ok(Some(message)) => {
+                    dbg!(&message);
+                    match client.propose(message).await {
+                        Ok(result) => println!("{result:?}"),
+                        Err(e) => println!("could not apply: {e}"),
+                    }
+                }
+                Ok(None) => {}
+                Err(s) => {
+                    panic!("{}", &s);
+                }
+            }
+        }
     }
 }
 
@@ -330,19 +475,20 @@ async fn start_server<S: StateMachine>(
     if let Some(socket_addr) = socket_addr {
         let statefile_path = extract_statefile_path(options);
 
-        let mut raft_state = persistence::load_or_default(EphemeralState {
+        let eph = EphemeralState {
             id: options.url.to_string(),
-            statefile_path,
             config,
             state_machine,
-        })
-        .await;
+        };
+        let mut raft_state = persistence::open(eph, &statefile_path)
+            .await
+            .unwrap_or_else(|e| panic!("could not load {}: {e}", statefile_path.to_str().unwrap()));
 
         if bootstrap {
-            raft_state.bootstrap();
+            raft_state.bootstrap().await.unwrap();
         }
 
-        raft_state.commit().await;
+        raft_state.commit().await.unwrap();
 
         server::start(raft_state, socket_addr).await
     } else {
@@ -352,3 +498,51 @@ async fn start_server<S: StateMachine>(
         );
     }
 }
+
+fn print_members(members: &MembersResponse) {
+    let width = members
+        .members
+        .iter()
+        .map(|member| member.id.len())
+        .chain(Some("MEMBER".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("{:width$}  ROLE", "MEMBER");
+    for member in &members.members {
+        let role = match member.role {
+            MemberRole::Voter => "voter",
+            MemberRole::Joining => "voter (joining)",
+            MemberRole::Leaving => "voter (leaving)",
+            MemberRole::Learner => "learner",
+        };
+        let leader = if member.leader { ", leader" } else { "" };
+        println!("{:width$}  {role}{leader}", member.id);
+    }
+
+    println!();
+    for (label, membership) in [
+        ("committed", &members.committed),
+        ("pending", &members.pending),
+    ] {
+        if let Some(membership) = membership {
+            println!("{label}: {}", describe_membership(membership));
+        }
+    }
+}
+
+fn describe_membership(membership: &Membership) -> String {
+    let join = |ids: &BTreeSet<String>| ids.iter().cloned().collect::<Vec<_>>().join(", ");
+
+    let mut description = match membership.index {
+        Some(index) => format!("index {index}, voters {}", join(&membership.voters)),
+        None => format!("from snapshot, voters {}", join(&membership.voters)),
+    };
+    if let Some(new_voters) = &membership.new_voters {
+        description += &format!(" -> {}", join(new_voters));
+    }
+    if !membership.learners.is_empty() {
+        description += &format!(", learners {}", join(&membership.learners));
+    }
+    description
+}
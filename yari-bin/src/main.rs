@@ -1,6 +1,8 @@
 mod cli;
 use yari::state_machine::in_memory_kv::InMemoryKV;
 
+// human-panic 1.x still names std::panic::PanicInfo
+#[allow(deprecated)]
 fn main() {
     if !cfg!(debug_assertions) {
         human_panic::setup_panic!();
@@ -0,0 +1,146 @@
+//! Serializes bytes as a base64 string, for use with
+//! `#[serde(with = "crate::base64")]`.
+//!
+//! Serde's default for `Vec<u8>` in JSON is an array of numbers, several
+//! times the size of the bytes themselves. Arrays are still accepted when
+//! deserializing, so servers that sent them can talk to ones that don't.
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserializer, Serializer,
+};
+use std::fmt::{self, Formatter};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The value of each character in [`ALPHABET`], and `INVALID` for the
+/// rest.
+const SEXTETS: [u8; 256] = {
+    let mut sextets = [INVALID; 256];
+    let mut i = 0;
+    while i < ALPHABET.len() {
+        sextets[ALPHABET[i] as usize] = i as u8;
+        i += 1;
+    }
+    sextets
+};
+
+const INVALID: u8 = 0xff;
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(BytesVisitor)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a base64 string or an array of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<u8>, E> {
+        decode(value).ok_or_else(|| E::custom("invalid base64"))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (u32::from(byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (n, chunk) in encoded.chunks(4).enumerate() {
+        let is_last = (n + 1) * 4 == encoded.len();
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return None;
+        }
+
+        let mut sextets = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let sextet = SEXTETS[c as usize];
+            if sextet == INVALID {
+                return None;
+            }
+            sextets = (sextets << 6) | u32::from(sextet);
+        }
+        sextets <<= 6 * padding;
+
+        let chunk_bytes = sextets.to_be_bytes();
+        bytes.extend_from_slice(&chunk_bytes[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_length_of_padding() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(decode(&encode(bytes)).as_deref(), Some(bytes));
+        }
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(encode(b"foob"), "Zm9vYg==");
+
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&all)), Some(all));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(decode("Zm9"), None);
+        assert_eq!(decode("Zm9v!mFy"), None);
+        assert_eq!(decode("Zg==Zm9v"), None);
+        assert_eq!(decode("Z==="), None);
+    }
+
+    #[test]
+    fn accepts_arrays_of_bytes() {
+        #[derive(serde::Deserialize)]
+        struct Chunk {
+            #[serde(with = "super")]
+            data: Vec<u8>,
+        }
+
+        let chunk: Chunk = serde_json::from_str(r#"{"data": [102, 111, 111]}"#).unwrap();
+        assert_eq!(chunk.data, b"foo");
+        let chunk: Chunk = serde_json::from_str(r#"{"data": "Zm9v"}"#).unwrap();
+        assert_eq!(chunk.data, b"foo");
+    }
+}
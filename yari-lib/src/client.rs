@@ -0,0 +1,208 @@
+//! A client for services that use a yari cluster.
+//!
+//! [`YariClient`] starts from a list of seed servers and remembers which
+//! of them is the leader once a request reaches it. Requests that land
+//! on a follower are redirected to the leader. Requests that fail
+//! because a server is unreachable or the cluster can't serve them yet,
+//! such as during an election, are retried on the next server with
+//! exponential backoff.
+
+use crate::{
+    rpc::{
+        ClientRequest, ClientResponse, QueryRequest, QueryResponse, RaftClient, ReadConsistency,
+    },
+    ClientSession, Error, Result, StateMachine,
+};
+use async_io::Timer;
+use async_lock::Mutex as AsyncMutex;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use trillium_http::{Method, Status};
+use url::Url;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+pub struct YariClient<SM> {
+    raft_client: RaftClient<SM>,
+    servers: Vec<Url>,
+    next_server: AtomicUsize,
+    leader: Mutex<Option<Url>>,
+    session: AsyncMutex<ClientSession>,
+    follow_redirects: bool,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl<SM> Debug for YariClient<SM> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("YariClient")
+            .field("servers", &self.servers)
+            .field("leader", &self.leader)
+            .field("follow_redirects", &self.follow_redirects)
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<SM: StateMachine> YariClient<SM> {
+    /// A client for the cluster that `servers` belong to, which needn't
+    /// include every member.
+    ///
+    /// # Panics
+    ///
+    /// If `servers` is empty.
+    pub fn new(servers: impl IntoIterator<Item = Url>) -> Self {
+        let servers: Vec<_> = servers.into_iter().collect();
+        assert!(!servers.is_empty(), "a client needs at least one server");
+        Self {
+            raft_client: RaftClient::new(),
+            servers,
+            next_server: AtomicUsize::new(0),
+            leader: Mutex::new(None),
+            session: AsyncMutex::new(ClientSession::new()),
+            follow_redirects: true,
+            retries: 10,
+            backoff: Duration::from_millis(50),
+        }
+    }
+
+    /// How many times a failed request is retried before giving up.
+    /// Defaults to 10.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Whether to follow a follower's redirect to the leader, rather than
+    /// moving on to the next server. Defaults to true.
+    pub fn with_follow_redirects(mut self, follow_redirects: bool) -> Self {
+        self.follow_redirects = follow_redirects;
+        self
+    }
+
+    /// The wait before the first retry, which doubles with each retry
+    /// after it. Defaults to 50ms.
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The leader, if a request has reached it.
+    pub fn leader(&self) -> Option<Url> {
+        self.leader.lock().unwrap().clone()
+    }
+
+    /// Applies `message` to the state machine and returns the result.
+    ///
+    /// Every proposal from this client is a command in one session, and
+    /// each retry of it reuses its sequence number, so `message` is
+    /// applied at most once however many times it is retried. A server
+    /// refuses a command older than the latest it has applied for the
+    /// session, so proposals are sent one at a time, in the order they
+    /// are made.
+    pub async fn propose(&self, message: SM::MessageType) -> Result<SM::ApplyResult> {
+        let mut session = self.session.lock().await;
+        let request = ClientRequest {
+            message,
+            session: Some(session.clone()),
+        };
+        // even a proposal that fails or is dropped may have been applied,
+        // so the next one can't reuse its sequence number
+        session.next();
+
+        let response: ClientResponse<SM::ApplyResult> =
+            self.send("/client", &request, true).await?;
+        Ok(response.result)
+    }
+
+    /// Answers `query` from the state machine, from state at least as up
+    /// to date as `read_consistency` requires.
+    pub async fn query(
+        &self,
+        query: SM::QueryType,
+        read_consistency: ReadConsistency,
+    ) -> Result<QueryResponse<SM::ApplyResult>> {
+        let request = QueryRequest {
+            query,
+            read_consistency,
+        };
+        // any server may answer a query, so it says nothing of the leader
+        self.send("/query", &request, false).await
+    }
+
+    /// The leader if it is known, and otherwise the next seed server.
+    fn server(&self) -> Url {
+        self.leader().unwrap_or_else(|| {
+            let next = self.next_server.load(Ordering::Relaxed);
+            self.servers[next % self.servers.len()].clone()
+        })
+    }
+
+    /// Stops sending requests to `server`, since it failed one.
+    fn skip(&self, server: &Url) {
+        let mut leader = self.leader.lock().unwrap();
+        if leader.as_ref() == Some(server) {
+            *leader = None;
+        } else {
+            self.next_server.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Posts `body` to `path`, retrying until it succeeds or the retries
+    /// run out. The server that answers is remembered as the leader if
+    /// `leader_only`.
+    async fn send<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        leader_only: bool,
+    ) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            let server = self.server();
+            let url = server.join(path).unwrap();
+            let error = match self
+                .raft_client
+                .send(Method::Post, url, Some(body), self.follow_redirects)
+                .await
+            {
+                Ok((url, mut conn)) => {
+                    if leader_only {
+                        *self.leader.lock().unwrap() = Some(url.join("/").unwrap());
+                    }
+                    return conn.response_json().await.map_err(Into::into);
+                }
+                Err(error) => error,
+            };
+
+            if attempts >= self.retries || !is_retryable(&error) {
+                return Err(error);
+            }
+            attempts += 1;
+            log::debug!("retrying {path} after {server} failed: {error}");
+
+            self.skip(&server);
+            Timer::after(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Whether another server, or the same one later, might succeed.
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::ErrorResponse(status, _) => *status == Status::ServiceUnavailable,
+        Error::UnexpectedStatus(error) => error.status() == Some(Status::ServiceUnavailable),
+        Error::Io(_) | Error::Http(_) | Error::Redirected(_) => true,
+        _ => false,
+    }
+}
@@ -1,4 +1,4 @@
-use crate::Result;
+use crate::{storage::DEFAULT_MAX_SEGMENT_SIZE, Result};
 use serde::Deserialize;
 use std::{fs::File, io::prelude::*, ops::Range, path::PathBuf, time::Duration};
 
@@ -20,10 +20,81 @@ impl From<TimeoutConfig> for Range<u64> {
     }
 }
 
+/// A snapshot is taken once either limit is reached by the entries
+/// applied since the previous one.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct SnapshotConfig {
+    max_entries: Option<usize>,
+    max_bytes: Option<u64>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(10_000),
+            max_bytes: None,
+        }
+    }
+}
+
+/// A server added to the cluster is promoted to voter once it has been
+/// within `max_lag` entries of the leader for `rounds` heartbeats in a
+/// row, and is given up on after `max_rounds`.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct CatchUpConfig {
+    max_lag: usize,
+    rounds: usize,
+    max_rounds: usize,
+}
+
+impl Default for CatchUpConfig {
+    fn default() -> Self {
+        Self {
+            max_lag: 100,
+            rounds: 3,
+            max_rounds: 200,
+        }
+    }
+}
+
+/// A client session is expired once `max_idle_entries` entries have been
+/// applied since its latest command. Each server checks this again as it
+/// applies the expiry, so every server must use the same value.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct SessionConfig {
+    max_idle_entries: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_entries: 10_000,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, Default, Copy)]
 pub struct Config {
     timeout: TimeoutConfig,
     heartbeat_interval: Option<u64>,
+    max_entries_per_append: Option<usize>,
+    max_bytes_per_append: Option<u64>,
+    max_in_flight_appends: Option<usize>,
+    pre_vote: Option<bool>,
+    max_clock_drift: Option<u64>,
+    proposal_timeout: Option<u64>,
+    proposal_linger: Option<u64>,
+    watch_history: Option<usize>,
+    max_segment_size: Option<u64>,
+    #[serde(default)]
+    snapshot: SnapshotConfig,
+    #[serde(default)]
+    catch_up: CatchUpConfig,
+    #[serde(default)]
+    sessions: SessionConfig,
 }
 
 impl Config {
@@ -41,4 +112,89 @@ impl Config {
     pub fn heartbeat_interval(&self) -> Duration {
         Duration::from_millis(self.heartbeat_interval.unwrap_or(self.timeout.min / 2))
     }
+
+    pub fn max_entries_per_append(&self) -> usize {
+        self.max_entries_per_append.unwrap_or(1000).max(1)
+    }
+
+    /// Appends carry at least one entry, even if it is larger than this.
+    pub fn max_bytes_per_append(&self) -> u64 {
+        self.max_bytes_per_append.unwrap_or(1024 * 1024)
+    }
+
+    /// How many appends may be awaiting a reply from one follower.
+    pub fn max_in_flight_appends(&self) -> usize {
+        self.max_in_flight_appends.unwrap_or(1).max(1)
+    }
+
+    /// Whether a server checks that it could win an election before
+    /// incrementing its term to start one.
+    pub fn pre_vote(&self) -> bool {
+        self.pre_vote.unwrap_or(false)
+    }
+
+    /// How long a follower that has heard from a leader ignores pre-votes
+    /// and votes.
+    pub fn min_election_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout.min)
+    }
+
+    /// How long after a quorum acknowledges a heartbeat the leader may
+    /// answer lease reads: the minimum election timeout, less the clock
+    /// drift allowed between servers.
+    pub fn lease_duration(&self) -> Duration {
+        let max_clock_drift = self.max_clock_drift.unwrap_or(self.timeout.min / 10);
+        self.min_election_timeout()
+            .saturating_sub(Duration::from_millis(max_clock_drift))
+    }
+
+    /// How long a leader stays in charge without hearing from a quorum.
+    pub fn max_election_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout.max)
+    }
+
+    /// How long a client waits for its entry to be applied before it is
+    /// told to retry.
+    pub fn proposal_timeout(&self) -> Duration {
+        Duration::from_millis(self.proposal_timeout.unwrap_or(self.timeout.max * 10))
+    }
+
+    /// How long the leader waits for more proposals to batch with one
+    /// that has just arrived. Proposals that arrive while a batch is
+    /// being written are batched regardless.
+    pub fn proposal_linger(&self) -> Duration {
+        Duration::from_millis(self.proposal_linger.unwrap_or(0))
+    }
+
+    /// How many applied messages are kept for watchers that start from
+    /// an earlier index, or reconnect after falling behind.
+    pub fn watch_history(&self) -> usize {
+        self.watch_history.unwrap_or(1000)
+    }
+
+    /// The size at which the log starts a new segment file.
+    pub fn max_segment_size(&self) -> u64 {
+        self.max_segment_size.unwrap_or(DEFAULT_MAX_SEGMENT_SIZE)
+    }
+
+    pub fn catch_up_max_lag(&self) -> usize {
+        self.catch_up.max_lag
+    }
+
+    pub fn catch_up_rounds(&self) -> usize {
+        self.catch_up.rounds.max(1)
+    }
+
+    pub fn catch_up_max_rounds(&self) -> usize {
+        self.catch_up.max_rounds
+    }
+
+    pub fn session_max_idle_entries(&self) -> usize {
+        self.sessions.max_idle_entries
+    }
+
+    pub fn snapshot_due(&self, entries: usize, bytes: u64) -> bool {
+        self.snapshot.max_entries.is_some_and(|max| entries >= max)
+            || self.snapshot.max_bytes.is_some_and(|max| bytes >= max)
+    }
 }
@@ -25,6 +25,45 @@ pub enum Error {
 
     #[error(transparent)]
     UnexpectedStatus(#[from] trillium_client::UnexpectedStatusError),
+
+    #[error("{1} ({0})")]
+    ErrorResponse(trillium_http::Status, String),
+
+    #[error("redirected to {0}")]
+    Redirected(url::Url),
+
+    #[error(transparent)]
+    Statefile(#[from] StatefileError),
+}
+
+#[derive(Error, Debug)]
+pub enum StatefileError {
+    #[error("statefile is truncated")]
+    Truncated,
+
+    #[error("statefile does not start with a yari header")]
+    MissingHeader,
+
+    #[error("statefile format version {0} is newer than this build supports")]
+    UnsupportedVersion(u32),
+
+    #[error("no migration registered from statefile format version {0}")]
+    MissingMigration(u32),
+
+    #[error("statefile was written for state machine {found}, expected {expected}")]
+    StateMachineMismatch { expected: String, found: String },
+
+    #[error("state machine type tag is {0} bytes, longer than a statefile header can hold")]
+    TypeTagTooLong(usize),
+
+    #[error("statefile checksum does not match its contents")]
+    ChecksumMismatch,
+
+    #[error("statefile payload could not be decoded: {0}")]
+    Payload(bincode::Error),
+
+    #[error("log segment {} is corrupt", .0.display())]
+    CorruptSegment(std::path::PathBuf),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
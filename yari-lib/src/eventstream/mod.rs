@@ -1,20 +1,17 @@
-use async_std::{
-    io::{BufReader, Read as AsyncRead},
-    stream::Stream,
-    task::{ready, Context, Poll},
+use futures_lite::{ready, AsyncRead, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
 };
-use std::{io, pin::Pin};
-use tide::{http::headers::CACHE_CONTROL, http::headers::CONTENT_TYPE, Body};
+use trillium_http::Body;
 
-pin_project_lite::pin_project! {
-    /// An SSE protocol encoder.
-    #[derive(Debug, Clone)]
-    pub struct Encoder<S> {
-        buf: Option<Vec<u8>>,
-        #[pin]
-        receiver: S,
-        cursor: usize,
-    }
+/// An SSE protocol encoder.
+#[derive(Debug, Clone)]
+pub struct Encoder<S> {
+    buf: Option<Vec<u8>>,
+    receiver: S,
+    cursor: usize,
 }
 
 impl<E, S> AsyncRead for Encoder<S>
@@ -28,7 +25,7 @@ where
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         // Request a new buffer if we don't have one yet.
-        if let None = self.buf {
+        if self.buf.is_none() {
             log::trace!("> waiting for event");
             self.buf = match ready!(Pin::new(&mut self.receiver).poll_next(cx)) {
                 Some(event) => {
@@ -44,10 +41,11 @@ where
         };
 
         // Write the current buffer to completion.
+        let cursor = self.cursor;
         let local_buf = self.buf.as_mut().unwrap();
         let local_len = local_buf.len();
-        let max = buf.len().min(local_buf.len());
-        buf[..max].clone_from_slice(&local_buf[..max]);
+        let max = buf.len().min(local_len - cursor);
+        buf[..max].clone_from_slice(&local_buf[cursor..cursor + max]);
 
         self.cursor += max;
 
@@ -70,7 +68,10 @@ pub trait Event {
 
 pub trait EventStream: Sized + Unpin + Send + Sync {
     fn into_encoder(self) -> Encoder<Self>;
-    fn into_response(self) -> tide::Response;
+
+    /// A response body that sends each event as it arrives, and ends
+    /// with the stream.
+    fn into_body(self) -> Body;
 }
 
 fn encode<E: Event>(event: &E) -> Vec<u8> {
@@ -81,28 +82,17 @@ fn encode<E: Event>(event: &E) -> Vec<u8> {
     if let Some(id) = event.id() {
         data.push_str(&format!("id:{}\n", id));
     }
-    data.push_str("data:");
     let mut data = data.into_bytes();
-    data.extend_from_slice(&event.data());
-    data.push(b'\n');
+    // a newline would end the field, so each line is a field of its own
+    for line in event.data().split(|&byte| byte == b'\n') {
+        data.extend_from_slice(b"data:");
+        data.extend_from_slice(line);
+        data.push(b'\n');
+    }
     data.push(b'\n');
     data
 }
 
-impl<S, E> Into<tide::Response> for Encoder<S>
-where
-    S: Sync + Send + Unpin + Stream<Item = E> + 'static,
-    E: Event,
-{
-    fn into(self) -> tide::Response {
-        tide::Response::builder(200)
-            .body(Body::from_reader(BufReader::new(self), None))
-            .header(CACHE_CONTROL, "no-cache")
-            .header(CONTENT_TYPE, "text/event-stream")
-            .into()
-    }
-}
-
 impl<E: Event, S: Send + Sync + Unpin + Stream<Item = E> + 'static> EventStream for S {
     fn into_encoder(self) -> Encoder<Self> {
         Encoder {
@@ -112,7 +102,7 @@ impl<E: Event, S: Send + Sync + Unpin + Stream<Item = E> + 'static> EventStream
         }
     }
 
-    fn into_response(self) -> tide::Response {
-        self.into_encoder().into_response()
+    fn into_body(self) -> Body {
+        Body::new_streaming(self.into_encoder(), None)
     }
 }
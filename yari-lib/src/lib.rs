@@ -1,13 +1,19 @@
+mod base64;
+pub mod client;
 pub mod config;
+mod eventstream;
 pub mod log;
 pub mod message_board;
 pub mod persistence;
 pub mod raft;
 pub mod rpc;
 pub mod server;
+pub mod sse_channel;
 pub mod state_machine;
+pub mod storage;
 
 pub use crate::log::*;
+pub use client::YariClient;
 pub use config::*;
 pub use raft::*;
 
@@ -1,24 +1,105 @@
 use crate::raft::{Index, Message, Term};
 use crate::rpc::AppendRequest;
-use serde::{Deserialize, Serialize};
+use crate::storage::{LogStore, MemoryLogStore};
+use crate::Result;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::marker::PhantomData;
 mod log_entry;
 use crate::TermIndex;
 pub use log_entry::*;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Log<MessageType> {
-    entries: Vec<LogEntry<MessageType>>,
+/// Why a follower rejected an append. If the follower has an entry at
+/// the previous index, `term` is its term and `index` is the first index
+/// of that term. Otherwise `term` is `None` and `index` is the follower's
+/// last index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conflict {
+    pub term: Option<Term>,
+    pub index: Option<Index>,
 }
 
-impl<MessageType: Message> Default for Log<MessageType> {
-    fn default() -> Self {
-        Log { entries: vec![] }
+/// The replicated log. Entries up to and including `snapshot` have been
+/// compacted away, and only the term of the last of them is remembered.
+#[derive(Debug)]
+pub struct Log<MessageType, Store = MemoryLogStore<MessageType>> {
+    store: Store,
+    snapshot: TermIndex,
+    bytes_since_snapshot: u64,
+    message_type: PhantomData<MessageType>,
+}
+
+impl<MessageType, Store> Serialize for Log<MessageType, Store>
+where
+    MessageType: Message,
+    Store: LogStore<MessageType>,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut log = serializer.serialize_struct("Log", 3)?;
+        log.serialize_field("snapshot_index", &self.snapshot_index())?;
+        log.serialize_field("last_index", &self.last_index())?;
+        log.serialize_field("last_term", &self.last_term())?;
+        log.end()
     }
 }
 
-impl<MessageType: Message> Log<MessageType> {
+impl<MessageType: Message, Store: LogStore<MessageType>> Log<MessageType, Store> {
+    pub fn new(store: Store) -> Self {
+        Self {
+            store,
+            snapshot: TermIndex(0, 0),
+            bytes_since_snapshot: 0,
+            message_type: PhantomData,
+        }
+    }
+
+    /// The last index covered by a snapshot, or zero if there is none.
+    pub fn snapshot_index(&self) -> Index {
+        self.snapshot.1
+    }
+
+    /// The number of entries after the snapshot.
+    pub fn entries_since_snapshot(&self) -> usize {
+        self.last_index().unwrap_or(0) - self.snapshot_index()
+    }
+
+    /// The approximate encoded size of the entries appended since the
+    /// last snapshot or restart.
+    pub fn bytes_since_snapshot(&self) -> u64 {
+        self.bytes_since_snapshot
+    }
+
+    /// Discards every entry up to and including `snapshot`, which must
+    /// have been captured by a snapshot.
+    pub async fn compact(&mut self, snapshot: TermIndex) -> Result<()> {
+        self.store.compact(snapshot.1).await?;
+        self.snapshot = snapshot;
+        self.bytes_since_snapshot = 0;
+        Ok(())
+    }
+
+    async fn append_to_store(&mut self, entries: Vec<LogEntry<MessageType>>) -> Result<()> {
+        for entry in &entries {
+            self.bytes_since_snapshot += bincode::serialized_size(entry)?;
+        }
+        self.store.append(entries).await
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
+        self.last_index().is_none()
+    }
+
+    pub fn term(&self, index: Index) -> Option<Term> {
+        let TermIndex(snapshot_term, snapshot_index) = self.snapshot;
+        match index {
+            0 => None,
+            index if index == snapshot_index => Some(snapshot_term),
+            index if index < snapshot_index => None,
+            index => self.store.term(index),
+        }
     }
 
     pub fn contains_term_at_index(
@@ -28,56 +109,113 @@ impl<MessageType: Message> Log<MessageType> {
     ) -> bool {
         match (expected_term, index) {
             (None, None) => true,
-            (Some(expected_term), Some(index)) => match self.get(index) {
-                Some(LogEntry { term, .. }) => expected_term == *term,
-                _ => false,
-            },
+            // compacted entries were committed, so they match any leader's
+            (Some(_), Some(index)) if index < self.snapshot_index() => true,
+            (Some(expected_term), Some(index)) => self.term(index) == Some(expected_term),
             (_, _) => false,
         }
     }
 
-    pub fn last_index_in_term(&self, term: Term) -> Option<Index> {
-        self.entries.iter().rev().find_map(|entry| {
-            if entry.term == term {
-                Some(entry.index)
-            } else {
-                None
+    fn conflict_with(&self, expected_term: Option<Term>, index: Option<Index>) -> Option<Conflict> {
+        if self.contains_term_at_index(expected_term, index) {
+            return None;
+        }
+
+        match index.and_then(|index| self.term(index)) {
+            Some(term) => {
+                let mut first_index = index.unwrap();
+                while first_index > 1 && self.term(first_index - 1) == Some(term) {
+                    first_index -= 1;
+                }
+
+                Some(Conflict {
+                    term: Some(term),
+                    index: Some(first_index),
+                })
             }
-        })
+
+            None => Some(Conflict {
+                term: None,
+                index: self.last_index(),
+            }),
+        }
+    }
+
+    pub fn last_index_in_term(&self, term: Term) -> Option<Index> {
+        let last_index = self.last_index()?;
+        (self.snapshot_index().max(1)..=last_index)
+            .rev()
+            .find(|index| self.term(*index) == Some(term))
     }
 
-    pub fn entries_starting_at(&self, index: Index) -> Option<&[LogEntry<MessageType>]> {
-        self.last_index().and_then(|last_index| {
-            if index <= last_index && index > 0 {
-                Some(&self.entries[index - 1..])
-            } else {
-                None
+    pub async fn entries_starting_at(
+        &self,
+        index: Index,
+    ) -> Result<Option<Vec<LogEntry<MessageType>>>> {
+        match self.last_index() {
+            Some(last_index) if index <= last_index && index > self.snapshot_index() => {
+                Ok(Some(self.store.get_range(index..last_index + 1).await?))
             }
-        })
+            _ => Ok(None),
+        }
     }
 
-    pub fn previous_entry_to(&self, index: Index) -> Option<&LogEntry<MessageType>> {
-        if index >= 1 {
-            self.get(index - 1)
-        } else {
-            None
+    /// Like `entries_starting_at`, but stops after `max_entries`, or
+    /// before the entries' encoded size would exceed `max_bytes`. The
+    /// first entry is always included.
+    pub async fn batch_starting_at(
+        &self,
+        index: Index,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Option<Vec<LogEntry<MessageType>>>> {
+        match self.last_index() {
+            Some(last_index) if index <= last_index && index > self.snapshot_index() => {
+                let end = (last_index + 1).min(index + max_entries);
+                let mut entries = self.store.get_range(index..end).await?;
+
+                let mut bytes = 0;
+                let mut len = 0;
+                for entry in &entries {
+                    let size = bincode::serialized_size(entry)?;
+                    if len > 0 && bytes + size > max_bytes {
+                        break;
+                    }
+                    bytes += size;
+                    len += 1;
+                }
+                entries.truncate(len);
+
+                Ok(Some(entries))
+            }
+            _ => Ok(None),
         }
     }
 
-    pub fn get(&self, index: Index) -> Option<&LogEntry<MessageType>> {
-        if index >= 1 {
-            self.entries.get(index - 1)
-        } else {
-            None
+    pub fn previous_entry_to(&self, index: Index) -> Option<TermIndex> {
+        let previous_index = index.checked_sub(1)?;
+        self.term(previous_index)
+            .map(|term| TermIndex(term, previous_index))
+    }
+
+    pub async fn get(&self, index: Index) -> Result<Option<LogEntry<MessageType>>> {
+        if index <= self.snapshot_index() {
+            return Ok(None);
         }
+        Ok(self.store.get_range(index..index + 1).await?.pop())
     }
 
-    pub fn truncate(&mut self, index: Index) {
-        self.entries.truncate(index - 1);
+    pub async fn truncate(&mut self, index: Index) -> Result<()> {
+        self.store
+            .truncate(index.max(self.snapshot_index() + 1))
+            .await
     }
 
     pub fn last_index(&self) -> Option<Index> {
-        self.entries.last().map(|e| e.index)
+        match self.store.last_index() {
+            Some(index) if index > self.snapshot_index() => Some(index),
+            _ => Some(self.snapshot_index()).filter(|index| *index > 0),
+        }
     }
 
     pub fn next_index(&self) -> Index {
@@ -85,7 +223,7 @@ impl<MessageType: Message> Log<MessageType> {
     }
 
     pub fn last_term(&self) -> Option<Term> {
-        self.entries.last().map(|e| e.term)
+        self.last_index().and_then(|index| self.term(index))
     }
 
     pub fn first_conflicting_index(
@@ -95,28 +233,32 @@ impl<MessageType: Message> Log<MessageType> {
         new_entries.as_ref().and_then(|messages| {
             messages
                 .iter()
-                .find(|LogEntry { term, index, .. }| match self.get(*index) {
-                    Some(entry @ LogEntry { .. }) => entry.term != *term,
+                .find(|LogEntry { term, index, .. }| match self.term(*index) {
+                    Some(existing_term) => existing_term != *term,
                     None => false,
                 })
                 .map(|LogEntry { index, .. }| *index)
         })
     }
 
-    pub fn append_new_entries_not_in_log(
+    pub async fn append_new_entries_not_in_log(
         &mut self,
         new_entries: Option<Vec<LogEntry<MessageType>>>,
-    ) {
+    ) -> Result<()> {
         if let Some(entries) = new_entries {
             let current_last_index = self.last_index().unwrap_or(0);
-            let new_entries_not_in_log = entries
+            let new_entries_not_in_log: Vec<_> = entries
                 .into_iter()
-                .filter(|LogEntry { index, .. }| *index > current_last_index);
-            self.entries.extend(new_entries_not_in_log);
+                .filter(|LogEntry { index, .. }| *index > current_last_index)
+                .collect();
+            if !new_entries_not_in_log.is_empty() {
+                self.append_to_store(new_entries_not_in_log).await?;
+            }
         }
+        Ok(())
     }
 
-    pub fn client_append(&mut self, term: Term, message: MessageType) -> TermIndex {
+    pub async fn client_append(&mut self, term: Term, message: MessageType) -> Result<TermIndex> {
         let index = self.next_index();
         let term_index = TermIndex(term, index);
         let log_entry = LogEntry {
@@ -125,20 +267,51 @@ impl<MessageType: Message> Log<MessageType> {
             index,
         };
 
-        self.entries.push(log_entry);
-        term_index
+        self.append_to_store(vec![log_entry]).await?;
+        Ok(term_index)
     }
 
-    pub fn append(&mut self, request: AppendRequest<MessageType>) -> bool {
-        if self.contains_term_at_index(request.previous_log_term, request.previous_log_index) {
+    /// Appends `messages` in a single write, returning where each one
+    /// landed.
+    pub async fn client_append_batch(
+        &mut self,
+        term: Term,
+        messages: Vec<MessageType>,
+    ) -> Result<Vec<TermIndex>> {
+        let first_index = self.next_index();
+        let entries: Vec<_> = messages
+            .into_iter()
+            .enumerate()
+            .map(|(offset, message)| LogEntry {
+                message,
+                term,
+                index: first_index + offset,
+            })
+            .collect();
+        let term_indexes = entries
+            .iter()
+            .map(|entry| TermIndex(term, entry.index))
+            .collect();
+
+        self.append_to_store(entries).await?;
+        Ok(term_indexes)
+    }
+
+    /// Appends the request's entries, or returns the reason they could
+    /// not be appended.
+    pub async fn append(
+        &mut self,
+        request: AppendRequest<MessageType>,
+    ) -> Result<Option<Conflict>> {
+        let conflict = self.conflict_with(request.previous_log_term, request.previous_log_index);
+        if conflict.is_none() {
             if let Some(index) = self.first_conflicting_index(&request.entries) {
-                self.truncate(index);
+                self.truncate(index).await?;
             }
 
-            self.append_new_entries_not_in_log(request.entries);
-            true
-        } else {
-            false
+            self.append_new_entries_not_in_log(request.entries).await?;
         }
+
+        Ok(conflict)
     }
 }
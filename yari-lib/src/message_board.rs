@@ -41,10 +41,24 @@ where
 
     pub async fn post(&mut self, k: &K, value: R) -> Result<(), R> {
         if let Some((_, sender)) = self.inner.remove_entry(k) {
-            sender.broadcast(value).await.unwrap();
+            // every listener may have gone away already
+            let _ = sender.broadcast(value).await;
             Ok(())
         } else {
             Err(value)
         }
     }
+
+    /// Posts `value` to the listeners of every key that `matches`.
+    pub fn post_where(&mut self, mut matches: impl FnMut(&K) -> bool, value: R) {
+        self.inner.retain(|k, sender| {
+            if matches(k) {
+                // nothing else is ever posted to this sender, so it has room
+                let _ = sender.try_broadcast(value.clone());
+                false
+            } else {
+                true
+            }
+        });
+    }
 }
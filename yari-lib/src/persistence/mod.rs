@@ -0,0 +1,121 @@
+use crate::{
+    raft::{EphemeralState, RaftMessage, StateMachine},
+    storage::{FileLogStore, FileStableStore},
+    RaftState, Result,
+};
+use async_fs::{File, OpenOptions};
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+pub mod statefile;
+
+pub type FileRaftState<SM> = RaftState<
+    SM,
+    <SM as StateMachine>::MessageType,
+    <SM as StateMachine>::ApplyResult,
+    FileLogStore<RaftMessage<<SM as StateMachine>::MessageType>>,
+    FileStableStore,
+>;
+
+pub fn path(id: &Url) -> Result<PathBuf> {
+    let mut path = env::current_dir()?;
+    let name = id
+        .port()
+        .map(|port| port.to_string())
+        .unwrap_or_else(|| id.host_str().unwrap().to_owned());
+
+    path.push(format!("{}.yari", name));
+
+    Ok(path)
+}
+
+/// The path of the log that accompanies the statefile at `statefile_path`.
+pub fn log_path(statefile_path: &Path) -> PathBuf {
+    with_suffix(statefile_path, ".log")
+}
+
+/// Opens the file-backed stores for `statefile_path`, starting from an
+/// empty state if nothing has been written there yet. A statefile that
+/// exists but cannot be loaded is an error.
+pub async fn open<SM: StateMachine>(
+    eph: EphemeralState<SM>,
+    statefile_path: &Path,
+) -> Result<FileRaftState<SM>> {
+    // the stable store is opened first, since migrating it may move
+    // entries into the log
+    let stable_store = FileStableStore::open(statefile_path, SM::type_tag()).await?;
+    let log_store = FileLogStore::open(&log_path(statefile_path), SM::type_tag())
+        .await?
+        .with_max_segment_size(eph.config.max_segment_size());
+    RaftState::new(eph, log_store, stable_store).await
+}
+
+/// Reads and decodes the statefile at `path`, returning the payload and
+/// whether it had to be migrated from an older format version.
+pub(crate) async fn read_statefile(path: &Path, type_tag: &str) -> Result<Option<(Vec<u8>, bool)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path).await?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).await?;
+    let migrated = statefile::version(&contents).is_some_and(|v| v < statefile::FORMAT_VERSION);
+    let payload = statefile::decode(path, type_tag, contents)?;
+    Ok(Some((payload, migrated)))
+}
+
+pub(crate) async fn write_statefile(path: &Path, type_tag: &str, payload: &[u8]) -> Result<()> {
+    write_atomically(path, &statefile::encode(type_tag, payload)?).await
+}
+
+/// Durably replaces the contents of `path`.
+///
+/// The data is written to a sibling temporary file, which is fsynced and
+/// then renamed over `path`, so a crash at any point leaves either the
+/// previous contents or the new ones, never a partial write.
+async fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let temp_path = with_suffix(path, ".tmp");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    async_fs::rename(&temp_path, path).await?;
+    sync_parent_dir(path).await
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+pub(crate) async fn sync_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent).await,
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) async fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
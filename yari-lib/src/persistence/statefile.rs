@@ -0,0 +1,454 @@
+//! The on-disk framing for statefiles.
+//!
+//! Every statefile starts with a fixed header:
+//!
+//! | field         | size      |                                          |
+//! |---------------|-----------|------------------------------------------|
+//! | magic         | 4 bytes   | `YARI`                                   |
+//! | version       | u32 le    | [`FORMAT_VERSION`] at the time of write  |
+//! | tag length    | u16 le    |                                          |
+//! | tag           | utf-8     | [`StateMachine::type_tag`]               |
+//! | payload len   | u64 le    |                                          |
+//! | checksum      | u32 le    | crc32 of the payload                     |
+//!
+//! followed by the bincode payload. Files written by an older version
+//! are brought up to date by running each registered [`Migration`] in
+//! turn, starting at the version recorded in the header.
+//!
+//! The log that version 2 moved out of the statefile is framed the same
+//! way, but its version is [`LOG_FORMAT_VERSION`], which statefile
+//! migrations never apply to.
+//!
+//! [`StateMachine::type_tag`]: crate::StateMachine::type_tag
+
+use crate::{
+    raft::{LegacyServerConfigChange, ServerConfigChange},
+    storage::HardState,
+    Index, Result, StatefileError, Term,
+};
+use std::{fs, path::Path};
+
+pub const MAGIC: &[u8; 4] = b"YARI";
+
+/// The version written by this build.
+pub const FORMAT_VERSION: u32 = 5;
+
+/// The version written into log files by this build.
+///
+/// A log file has always held a bincode `Vec<LogEntry>`, but until it had
+/// a version of its own it was stamped with the statefile version of the
+/// build that wrote it, which was at most 5. Every version up to this one
+/// is read the same way.
+pub const LOG_FORMAT_VERSION: u32 = 5;
+
+/// What a migration knows about the file it is upgrading.
+pub struct MigrationContext<'a> {
+    pub path: &'a Path,
+    pub type_tag: &'a str,
+}
+
+/// An upgrade of a payload from version `from` to version `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    pub migrate: fn(&MigrationContext<'_>, Vec<u8>) -> Result<Vec<u8>>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        migrate: split_log_into_log_file,
+    },
+    // version 3 follows the hard state with the metadata of an optional
+    // snapshot, which bincode encodes as a single zero byte when absent
+    Migration {
+        from: 2,
+        migrate: |_, mut payload| {
+            payload.push(0);
+            Ok(payload)
+        },
+    },
+    Migration {
+        from: 3,
+        migrate: add_learners_to_snapshot_config,
+    },
+    Migration {
+        from: 4,
+        migrate: add_sessions_to_snapshot_meta,
+    },
+];
+
+/// Version 5 added client sessions to [`SnapshotMeta`], after its
+/// config. An existing snapshot gets an empty byte string, which is read
+/// back as no sessions.
+///
+/// [`SnapshotMeta`]: crate::storage::SnapshotMeta
+fn add_sessions_to_snapshot_meta(
+    _: &MigrationContext<'_>,
+    mut payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    type Version4 = (HardState, Option<(Index, Term, Option<ServerConfigChange>)>);
+
+    let (_, snapshot): Version4 =
+        bincode::deserialize(&payload).map_err(StatefileError::Payload)?;
+    if snapshot.is_some() {
+        payload.extend_from_slice(&0u64.to_le_bytes());
+    }
+    Ok(payload)
+}
+
+/// Version 4 added learners to [`ServerConfigChange`]. A snapshot's
+/// config is the last thing in the payload, so the learners of an
+/// existing one are appended as an empty set.
+///
+/// [`ServerConfigChange`]: crate::raft::ServerConfigChange
+fn add_learners_to_snapshot_config(
+    _: &MigrationContext<'_>,
+    mut payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    type Version3 = (
+        HardState,
+        Option<(Index, Term, Option<LegacyServerConfigChange>)>,
+    );
+
+    let (_, snapshot): Version3 =
+        bincode::deserialize(&payload).map_err(StatefileError::Payload)?;
+    if let Some((_, _, Some(_))) = snapshot {
+        payload.extend_from_slice(&0u64.to_le_bytes());
+    }
+    Ok(payload)
+}
+
+/// Version 1 statefiles held the term, the vote and the entire log.
+/// Version 2 statefiles hold only the [`HardState`], and the log lives
+/// in its own file beside the statefile.
+fn split_log_into_log_file(context: &MigrationContext<'_>, payload: Vec<u8>) -> Result<Vec<u8>> {
+    let hard_state: HardState = bincode::deserialize(&payload).map_err(StatefileError::Payload)?;
+    let hard_state_len = bincode::serialized_size(&hard_state)? as usize;
+
+    // the log file may already exist if a previous run migrated the
+    // log but crashed before rewriting the statefile
+    let log_path = super::log_path(context.path);
+    if !log_path.exists() {
+        let log = encode_log(context.type_tag, &payload[hard_state_len..])?;
+        let temp_path = super::with_suffix(&log_path, ".tmp");
+        fs::write(&temp_path, log)?;
+        fs::File::open(&temp_path)?.sync_all()?;
+        fs::rename(&temp_path, &log_path)?;
+    }
+
+    Ok(payload[..hard_state_len].to_vec())
+}
+
+pub fn encode(type_tag: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    frame(FORMAT_VERSION, type_tag, payload)
+}
+
+/// Frames a log file's payload, stamped with [`LOG_FORMAT_VERSION`].
+pub fn encode_log(type_tag: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    frame(LOG_FORMAT_VERSION, type_tag, payload)
+}
+
+fn frame(version: u32, type_tag: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    let tag = type_tag.as_bytes();
+    let tag_len =
+        u16::try_from(tag.len()).map_err(|_| StatefileError::TypeTagTooLong(tag.len()))?;
+    let mut bytes = Vec::with_capacity(22 + tag.len() + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&tag_len.to_le_bytes());
+    bytes.extend_from_slice(tag);
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+/// The format version recorded in `bytes`, without validating anything
+/// else, or `None` if they don't start with a header.
+pub fn version(bytes: &[u8]) -> Option<u32> {
+    match bytes.get(MAGIC.len()..MAGIC.len() + 4) {
+        Some(version) if bytes.starts_with(MAGIC) => {
+            Some(u32::from_le_bytes(version.try_into().unwrap()))
+        }
+        _ => None,
+    }
+}
+
+/// Validates the header and returns the payload, migrated to
+/// [`FORMAT_VERSION`].
+pub fn decode(path: &Path, type_tag: &str, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let context = MigrationContext { path, type_tag };
+    let (version, payload) = unframe(type_tag, FORMAT_VERSION, &bytes)?;
+    migrate(&context, version, payload.to_vec())
+}
+
+/// Validates the header of a log file and returns its payload, which no
+/// version so far has needed to migrate.
+pub fn decode_log(type_tag: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+    let (_, payload) = unframe(type_tag, LOG_FORMAT_VERSION, bytes)?;
+    Ok(payload.to_vec())
+}
+
+/// Validates the header and returns the version and payload, refusing
+/// versions newer than `max_version`.
+fn unframe<'a>(type_tag: &str, max_version: u32, bytes: &'a [u8]) -> Result<(u32, &'a [u8])> {
+    // files without a header were only ever written before statefiles
+    // were versioned, in layouts that can't be told apart
+    if !bytes.starts_with(MAGIC) {
+        return Err(StatefileError::MissingHeader.into());
+    }
+
+    let mut reader = Reader(&bytes[MAGIC.len()..]);
+    let version = u32::from_le_bytes(reader.take()?);
+
+    if version > max_version {
+        return Err(StatefileError::UnsupportedVersion(version).into());
+    }
+
+    let tag_len = u16::from_le_bytes(reader.take()?) as usize;
+    let tag = String::from_utf8_lossy(reader.take_slice(tag_len)?);
+    if tag != type_tag {
+        return Err(StatefileError::StateMachineMismatch {
+            expected: type_tag.to_string(),
+            found: tag.into_owned(),
+        }
+        .into());
+    }
+
+    let payload_len = u64::from_le_bytes(reader.take()?) as usize;
+    let checksum = u32::from_le_bytes(reader.take()?);
+    let payload = reader.take_slice(payload_len)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(StatefileError::ChecksumMismatch.into());
+    }
+
+    Ok((version, payload))
+}
+
+fn migrate(
+    context: &MigrationContext<'_>,
+    mut version: u32,
+    mut payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    while version < FORMAT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(StatefileError::MissingMigration(version))?;
+
+        log::info!("migrating statefile from version {version}");
+        payload = (migration.migrate)(context, payload)?;
+        version += 1;
+    }
+
+    Ok(payload)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(StatefileError::Truncated.into());
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take_slice(N)?);
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{raft::RaftMessage, storage::SnapshotMeta, Error, LogEntry};
+    use std::{collections::HashSet, path::PathBuf};
+
+    const TAG: &str = "test state machine";
+
+    /// A file as a build writing `version` would have written it.
+    fn encode_version(version: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = encode(TAG, payload).unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&version.to_le_bytes());
+        bytes
+    }
+
+    fn decode_error(bytes: Vec<u8>) -> StatefileError {
+        match decode(Path::new("unused.yari"), TAG, bytes) {
+            Err(Error::Statefile(e)) => e,
+            other => panic!("expected a statefile error, got {other:?}"),
+        }
+    }
+
+    /// A statefile path in an empty directory of its own.
+    fn temp_statefile(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yari-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("8000.yari")
+    }
+
+    fn hard_state() -> HardState {
+        HardState {
+            current_term: 3,
+            voted_for: Some("http://127.0.0.1:8001/".into()),
+        }
+    }
+
+    /// Decodes a file written by `version`, migrating it to the current
+    /// payload.
+    fn migrate_from(version: u32, payload: &[u8]) -> (HardState, Option<SnapshotMeta>) {
+        let bytes = decode(
+            Path::new("unused.yari"),
+            TAG,
+            encode_version(version, payload),
+        );
+        bincode::deserialize(&bytes.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_the_current_version() {
+        let bytes = encode(TAG, b"payload").unwrap();
+        assert_eq!(version(&bytes), Some(FORMAT_VERSION));
+        let payload = decode(Path::new("unused.yari"), TAG, bytes).unwrap();
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn refuses_files_it_cannot_trust() {
+        let bytes = encode(TAG, b"payload").unwrap();
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_error(corrupt),
+            StatefileError::ChecksumMismatch
+        ));
+
+        assert!(matches!(
+            decode_error(bytes[..bytes.len() - 1].to_vec()),
+            StatefileError::Truncated
+        ));
+
+        assert!(matches!(
+            decode_error(encode_version(FORMAT_VERSION + 1, b"payload")),
+            StatefileError::UnsupportedVersion(version) if version == FORMAT_VERSION + 1
+        ));
+
+        let other = encode("another state machine", b"payload").unwrap();
+        assert!(matches!(
+            decode_error(other),
+            StatefileError::StateMachineMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn refuses_type_tags_too_long_for_the_header() {
+        let tag = "x".repeat(usize::from(u16::MAX) + 1);
+        assert!(matches!(
+            encode(&tag, b"payload"),
+            Err(Error::Statefile(StatefileError::TypeTagTooLong(len))) if len == tag.len()
+        ));
+    }
+
+    #[test]
+    fn moves_the_log_out_of_version_1_files() {
+        let log = bincode::serialize(&vec![LogEntry {
+            index: 1,
+            term: 3,
+            message: RaftMessage::<()>::Blank,
+        }])
+        .unwrap();
+        // the term, the vote and the log, in the order version 1 wrote them
+        let mut payload = bincode::serialize(&hard_state()).unwrap();
+        payload.extend_from_slice(&log);
+
+        let path = temp_statefile("version-1");
+        let migrated = decode(&path, TAG, encode_version(1, &payload)).unwrap();
+
+        let (state, snapshot): (HardState, Option<SnapshotMeta>) =
+            bincode::deserialize(&migrated).unwrap();
+        assert_eq!(state, hard_state());
+        assert!(snapshot.is_none());
+
+        let log_file = fs::read(super::super::log_path(&path)).unwrap();
+        assert_eq!(version(&log_file), Some(LOG_FORMAT_VERSION));
+        assert_eq!(decode_log(TAG, &log_file).unwrap(), log);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn refuses_files_without_a_header() {
+        // the whole raft state, as the unversioned `load` expected it
+        let unversioned = bincode::serialize(&(
+            "http://127.0.0.1:8000/",
+            Vec::<LogEntry<RaftMessage<()>>>::new(),
+            hard_state().current_term,
+            hard_state().voted_for,
+            None::<()>,
+            (HashSet::<String>::new(), None::<()>),
+        ))
+        .unwrap();
+        assert!(matches!(
+            decode_error(unversioned),
+            StatefileError::MissingHeader
+        ));
+    }
+
+    #[test]
+    fn gives_version_2_files_no_snapshot() {
+        let payload = bincode::serialize(&hard_state()).unwrap();
+        let (state, snapshot) = migrate_from(2, &payload);
+        assert_eq!(state, hard_state());
+        assert!(snapshot.is_none());
+    }
+
+    #[test]
+    fn gives_version_3_snapshot_configs_no_learners() {
+        let voters: HashSet<String> = ["a", "b"].map(String::from).into();
+        let new_voters: HashSet<String> = ["b", "c"].map(String::from).into();
+        let config = Some((voters.clone(), Some(new_voters.clone())));
+        let payload = bincode::serialize(&(hard_state(), Some((5u64, 2u64, config)))).unwrap();
+
+        let (state, snapshot) = migrate_from(3, &payload);
+        assert_eq!(state, hard_state());
+        let snapshot = snapshot.unwrap();
+        assert_eq!(
+            (snapshot.last_included_index, snapshot.last_included_term),
+            (5, 2)
+        );
+        let membership = snapshot.config.unwrap().membership(None);
+        assert_eq!(membership.voters, voters.into_iter().collect());
+        assert_eq!(
+            membership.new_voters,
+            Some(new_voters.into_iter().collect())
+        );
+        assert!(membership.learners.is_empty());
+
+        let no_config = None::<(HashSet<String>, Option<HashSet<String>>)>;
+        let payload = bincode::serialize(&(hard_state(), Some((5u64, 2u64, no_config)))).unwrap();
+        assert!(migrate_from(3, &payload).1.unwrap().config.is_none());
+    }
+
+    #[test]
+    fn gives_version_4_snapshots_no_sessions() {
+        for config in [None, Some(ServerConfigChange::default())] {
+            let has_config = config.is_some();
+            let payload = bincode::serialize(&(hard_state(), Some((5u64, 2u64, config)))).unwrap();
+
+            let (state, snapshot) = migrate_from(4, &payload);
+            assert_eq!(state, hard_state());
+            let snapshot = snapshot.unwrap();
+            assert_eq!(snapshot.config.is_some(), has_config);
+            assert!(snapshot.sessions.is_empty());
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Bringing a new server up to date before it becomes a voter.
+//!
+//! A server added to the cluster joins as a learner first. Each heartbeat
+//! round the leader checks how far behind it is, and promotes it once it
+//! has stayed close enough for several rounds in a row, so that adding it
+//! never leaves the cluster waiting on a server with an empty log.
+
+use super::{RaftMessage, RaftState, StateMachine};
+use crate::{
+    storage::{LogStore, StableStore},
+    Result,
+};
+use async_channel::{Receiver, Sender};
+use std::time::Instant;
+
+/// Whether a server caught up and was promoted, or why it wasn't.
+pub type CatchUpResult = std::result::Result<(), String>;
+
+/// A server being brought up to date.
+#[derive(Debug)]
+pub(crate) struct CatchUp {
+    rounds: usize,
+    rounds_caught_up: usize,
+    round_started: Option<Instant>,
+    added_as_learner: bool,
+    done: Sender<CatchUpResult>,
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    /// Adds `id` as a learner and promotes it to voter once it has caught
+    /// up, returning a receiver for the outcome.
+    pub async fn member_add_after_catch_up(&mut self, id: &str) -> Result<Receiver<CatchUpResult>> {
+        let (done, receiver) = async_channel::bounded(1);
+        if self.servers.is_voter(id) {
+            let _ = done.try_send(Ok(()));
+            return Ok(receiver);
+        }
+
+        let added_as_learner = self.learner_add(id).await?;
+        if let Some(previous) = self.catching_up.insert(
+            id.to_string(),
+            CatchUp {
+                rounds: 0,
+                rounds_caught_up: 0,
+                round_started: None,
+                added_as_learner,
+                done,
+            },
+        ) {
+            let _ = previous
+                .done
+                .try_send(Err(format!("superseded by another request to add {id}")));
+        }
+
+        Ok(receiver)
+    }
+
+    /// Counts a round for each server catching up, promoting those that
+    /// have stayed close enough to the leader and giving up on those
+    /// that are out of rounds.
+    pub(crate) async fn check_catch_up(&mut self) -> Result<()> {
+        let last_index = self.log.last_index().unwrap_or(0);
+        let max_lag = self.config.catch_up_max_lag();
+
+        let mut promote = vec![];
+        let mut give_up = vec![];
+        for (id, catch_up) in &mut self.catching_up {
+            let follower = self
+                .follower_state
+                .as_ref()
+                .and_then(|followers| followers.get(id));
+            let lag = last_index.saturating_sub(follower.map_or(0, |f| f.match_index));
+
+            // a round only counts if the server replied during it, and
+            // rounds start once the leader is replicating to it
+            let replied = match (follower, catch_up.round_started) {
+                (Some(follower), Some(started)) => follower.last_contact > started,
+                _ => false,
+            };
+            if follower.is_some() {
+                catch_up.round_started = Some(Instant::now());
+            }
+
+            catch_up.rounds += 1;
+            if replied && lag <= max_lag {
+                catch_up.rounds_caught_up += 1;
+            } else {
+                catch_up.rounds_caught_up = 0;
+            }
+
+            if catch_up.rounds_caught_up >= self.config.catch_up_rounds() {
+                promote.push(id.clone());
+            } else if catch_up.rounds >= self.config.catch_up_max_rounds() {
+                give_up.push((
+                    id.clone(),
+                    format!(
+                        "{id} did not catch up within {} rounds, and is {lag} entries behind",
+                        catch_up.rounds
+                    ),
+                ));
+            }
+        }
+
+        for id in promote {
+            // a server that caught up is promoted once any other
+            // membership change, such as another promotion, has finished
+            if self.is_changing_membership() {
+                continue;
+            }
+
+            let catch_up = self.catching_up.remove(&id).unwrap();
+            let result = if self.learner_promote(&id).await? {
+                log::info!("{}: {id} caught up, promoting it", self.id);
+                Ok(())
+            } else {
+                Err(format!("{id} caught up, but is no longer a learner"))
+            };
+            let _ = catch_up.done.try_send(result);
+        }
+
+        for (id, reason) in give_up {
+            let catch_up = self.catching_up.remove(&id).unwrap();
+            log::info!("{}: {reason}", self.id);
+            if catch_up.added_as_learner && !self.member_remove(&id).await? {
+                log::warn!(
+                    "{}: {id} is left as a learner, since another membership change is in progress",
+                    self.id
+                );
+            }
+            let _ = catch_up.done.try_send(Err(reason));
+        }
+
+        Ok(())
+    }
+
+    /// Fails every server still catching up, since only the leader
+    /// follows their progress.
+    pub(crate) fn abandon_catch_ups(&mut self) {
+        for (id, catch_up) in self.catching_up.drain() {
+            let _ = catch_up
+                .done
+                .try_send(Err(format!("leadership was lost before {id} caught up")));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Candidate-side elections.
+//!
+//! Votes are collected without holding the raft lock, so that two
+//! candidates asking each other for votes can both answer, and each round
+//! gives up after an election timeout rather than waiting on a server
+//! that never replies.
+
+use super::{Blank, ElectionResult, Followers, RaftMessage, RaftState, StateMachine, Term};
+use crate::{
+    rpc::{RaftClient, VoteRequest},
+    storage::{LogStore, StableStore},
+    Result,
+};
+use async_io::Timer;
+use futures_lite::FutureExt;
+use std::time::Duration;
+
+/// A vote or pre-vote request, and the servers to send it to.
+pub(crate) struct Ballot {
+    followers: Followers,
+    request: VoteRequest,
+}
+
+impl Ballot {
+    /// Whether a quorum grants the request within `timeout`.
+    pub(crate) async fn collect<SM: StateMachine>(
+        &self,
+        client: &RaftClient<SM>,
+        timeout: Duration,
+    ) -> bool {
+        let votes = self.followers.meets_quorum_async(|follower| {
+            let client = client.clone();
+            let i = follower.identifier.clone();
+            let vr = self.request.clone();
+            async move {
+                let response = if vr.pre_vote {
+                    client.request_pre_vote(&i, &vr).await
+                } else {
+                    client.request_vote(&i, &vr).await
+                };
+
+                match response {
+                    Ok(response) => response.vote_granted,
+                    _ => false,
+                }
+            }
+        });
+
+        let timeout = async {
+            Timer::after(timeout).await;
+            false
+        };
+
+        votes.or(timeout).await
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    fn ballot(&self, term: Term, pre_vote: bool, leadership_transfer: bool) -> Ballot {
+        Ballot {
+            followers: Followers::from_servers(&self.servers, &self.id, self.log.next_index()),
+            request: VoteRequest {
+                candidate_id: self.id.clone(),
+                last_log_index: self.log.last_index(),
+                last_log_term: self.log.last_term(),
+                term,
+                pre_vote,
+                leadership_transfer,
+            },
+        }
+    }
+
+    /// A pre-vote for the next term, or `None` if this server is not
+    /// eligible to be elected.
+    pub(crate) fn pre_vote_ballot(&self) -> Option<Ballot> {
+        self.servers
+            .is_voter(&self.id)
+            .then(|| self.ballot(self.current_term + 1, true, false))
+    }
+
+    /// Becomes a candidate in the next term, returning the vote to hold,
+    /// or `None` if this server is not eligible to be elected.
+    pub(crate) async fn start_election(
+        &mut self,
+        leadership_transfer: bool,
+    ) -> Result<Option<Ballot>> {
+        if !self.servers.is_voter(&self.id) {
+            return Ok(None);
+        }
+
+        self.current_term += 1;
+        log::trace!(
+            "{}: starting election, term: {}",
+            self.id(),
+            self.current_term
+        );
+        self.voted_for = Some(self.id.clone());
+        self.candidate = true;
+        self.leader_id_for_client_redirection = None;
+        self.persist().await?;
+
+        Ok(Some(self.ballot(
+            self.current_term,
+            false,
+            leadership_transfer,
+        )))
+    }
+
+    /// Becomes leader if the vote was won and nothing has superseded this
+    /// candidacy in the meantime.
+    pub(crate) async fn finish_election(
+        &mut self,
+        ballot: Ballot,
+        won: bool,
+    ) -> Result<ElectionResult> {
+        if !won || ballot.request.term != self.current_term || !self.is_candidate() {
+            return Ok(ElectionResult::FailedQuorum);
+        }
+
+        self.candidate = false;
+        self.follower_state = Some(Followers::from_servers(
+            &self.servers,
+            &self.id,
+            self.log.next_index(),
+        ));
+        self.log.client_append(self.current_term, Blank).await?;
+        Ok(ElectionResult::Elected)
+    }
+}
@@ -1,17 +1,18 @@
+use super::{proposals, replication::Replicators, ArcRaft};
 use crate::{
-    Config, ElectionResult, RaftState,
+    storage::{LogStore, StableStore},
+    Config, ElectionResult, RaftMessage, Result,
     Role::{self, *},
     StateMachine,
 };
 
 use async_io::Timer;
-use async_lock::RwLock;
 use futures_lite::FutureExt;
 //use futures_util::FutureExt;
-use std::{sync::Arc, time::Duration};
+use std::time::Duration;
 
-pub struct ElectionThread<SM, MT, AR> {
-    raft_state: Arc<RwLock<RaftState<SM, MT, AR>>>,
+pub struct ElectionThread<SM: StateMachine, L, S> {
+    raft_state: ArcRaft<SM, L, S>,
     interrupt_channel: super::Receiver<()>,
 }
 
@@ -21,12 +22,13 @@ enum TimerState {
     Interrupted,
 }
 
-type ArcRaft<SM> = Arc<
-    RwLock<RaftState<SM, <SM as StateMachine>::MessageType, <SM as StateMachine>::ApplyResult>>,
->;
-
-impl<SM: StateMachine> ElectionThread<SM, SM::MessageType, SM::ApplyResult> {
-    async fn new(raft_state: ArcRaft<SM>) -> Self {
+impl<SM, L, S> ElectionThread<SM, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    async fn new(raft_state: ArcRaft<SM, L, S>) -> Self {
         let interrupt_channel = raft_state.write().await.take_interrupt_channel().unwrap();
         Self {
             raft_state,
@@ -57,9 +59,17 @@ impl<SM: StateMachine> ElectionThread<SM, SM::MessageType, SM::ApplyResult> {
         self.wait(heartbeat_interval).await;
     }
 
-    async fn leader_loop(&self) {
+    async fn leader_loop(&self, replicators: &mut Replicators) {
+        let (term, followers) = {
+            let raft = self.raft_state.read().await;
+            (raft.current_term(), raft.follower_ids())
+        };
+        replicators.update(&self.raft_state, term, followers);
+
         self.wait_heartbeat_interval().await;
-        self.send_appends_or_heartbeats().await;
+        if let Err(e) = self.raft_state.write().await.leader_tick().await {
+            log::error!("failed to advance commit index: {e}");
+        }
     }
 
     // async fn solitary_loop(&self) {
@@ -81,11 +91,15 @@ impl<SM: StateMachine> ElectionThread<SM, SM::MessageType, SM::ApplyResult> {
 
     async fn follower_loop(&self) {
         let duration = self.generate_election_timeout().await;
-        if let TimerState::TimedOut = self.wait(duration).await {
-            match self.start_election().await {
-                ElectionResult::Elected => self.log("successfully got elected").await,
-                ElectionResult::FailedQuorum => self.log("failed to get elected").await,
-                ElectionResult::Ineligible => self.log("was ineligible to get elected").await,
+        let timer_state = self.wait(duration).await;
+        let requested = self.raft_state.write().await.take_election_request();
+        if requested || timer_state == TimerState::TimedOut {
+            match self.start_election(requested).await {
+                Ok(ElectionResult::Elected) => self.log("successfully got elected").await,
+                Ok(ElectionResult::FailedQuorum) => self.log("failed to get elected").await,
+                Ok(ElectionResult::FailedPreVote) => self.log("failed to win a pre-vote").await,
+                Ok(ElectionResult::Ineligible) => self.log("was ineligible to get elected").await,
+                Err(e) => log::error!("failed to run election: {e}"),
             }
         }
     }
@@ -94,29 +108,60 @@ impl<SM: StateMachine> ElectionThread<SM, SM::MessageType, SM::ApplyResult> {
         self.raft_state.clone().read().await.role()
     }
 
-    async fn start_election(&self) -> ElectionResult {
-        self.raft_state.clone().write().await.start_election().await
-    }
+    /// Runs an election, skipping the pre-vote when the leader asked for
+    /// it, since the other servers will have heard from that leader.
+    async fn start_election(&self, leadership_transfer: bool) -> Result<ElectionResult> {
+        let (client, config) = {
+            let raft = self.raft_state.read().await;
+            (raft.client(), raft.config)
+        };
+        let timeout = config.max_election_timeout();
+
+        if !leadership_transfer && config.pre_vote() {
+            let Some(ballot) = self.raft_state.read().await.pre_vote_ballot() else {
+                return Ok(ElectionResult::Ineligible);
+            };
+
+            if !ballot.collect(&client, timeout).await {
+                return Ok(ElectionResult::FailedPreVote);
+            }
+        }
+
+        let Some(ballot) = self
+            .raft_state
+            .write()
+            .await
+            .start_election(leadership_transfer)
+            .await?
+        else {
+            return Ok(ElectionResult::Ineligible);
+        };
 
-    async fn send_appends_or_heartbeats(&self) {
+        let won = ballot.collect(&client, timeout).await;
         self.raft_state
             .write()
             .await
-            .send_appends_or_heartbeats()
+            .finish_election(ballot, won)
             .await
     }
 
     async fn run(&self) {
         self.log("starting election thread").await;
+        let _proposals =
+            async_global_executor::spawn(proposals::append_batches(self.raft_state.clone()));
+        let mut replicators = Replicators::default();
         loop {
             match self.role().await {
-                Leader | Solitary => self.leader_loop().await,
-                Follower | Candidate => self.follower_loop().await,
+                Leader | Solitary => self.leader_loop(&mut replicators).await,
+                Follower | Candidate => {
+                    replicators.clear();
+                    self.follower_loop().await
+                }
             }
         }
     }
 
-    pub async fn spawn(state: ArcRaft<SM>) {
+    pub async fn spawn(state: ArcRaft<SM, L, S>) {
         Self::new(state).await.run().await
     }
 }
@@ -1,12 +1,12 @@
-use crate::raft::{Index, Servers};
-use futures_lite::StreamExt;
+use crate::raft::{Index, Quorum, Servers};
 use serde::Serialize;
 use std::collections::{
     hash_map::{Values, ValuesMut},
-    HashMap,
+    HashMap, HashSet,
 };
 use std::future::Future;
 use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
 use unicycle::FuturesUnordered;
 
 #[derive(Debug, Clone, Serialize)]
@@ -14,6 +14,17 @@ pub struct FollowerState {
     pub identifier: String,
     pub next_index: Index,
     pub match_index: Index,
+
+    /// Learners are replicated to but don't count toward any quorum.
+    pub learner: bool,
+
+    /// When this follower last replied to the leader.
+    #[serde(skip)]
+    pub last_contact: Instant,
+
+    /// When the newest request this follower has replied to was sent.
+    #[serde(skip)]
+    pub acknowledged: Option<Instant>,
 }
 
 impl Hash for FollowerState {
@@ -36,89 +47,146 @@ impl FollowerState {
     }
 }
 
+/// The leader's view of the other servers, and the quorum they make up
+/// together with the leader.
 #[derive(Debug, Default, Serialize)]
-pub struct Followers(HashMap<String, FollowerState>);
+#[serde(transparent)]
+pub struct Followers {
+    followers: HashMap<String, FollowerState>,
+    #[serde(skip)]
+    own_id: String,
+    #[serde(skip)]
+    quorum: Quorum,
+}
+
 impl Followers {
     pub fn from_servers(servers: &Servers, own_id: &str, next_index: Index) -> Self {
-        let mut followers = Followers::default();
-        for server in servers {
-            if server != own_id {
-                followers.add_follower(server, next_index)
-            }
-        }
+        let mut followers = Followers {
+            own_id: own_id.to_string(),
+            ..Followers::default()
+        };
+        followers.update_from_servers(servers, own_id, next_index);
         followers
     }
 
     pub fn update_from_servers(&mut self, servers: &Servers, own_id: &str, next_index: Index) {
-        self.0.retain(|id, _| servers.contains(id));
+        self.followers.retain(|id, _| servers.contains(id));
+        self.quorum = servers.quorum();
 
-        for server in servers {
+        let voters = servers.into_iter().map(|server| (server, false));
+        let learners = servers.learners().map(|server| (server, true));
+        for (server, learner) in voters.chain(learners) {
             if server != own_id {
-                self.add_follower(server, next_index)
+                self.add_follower(server, next_index);
+                self.followers.get_mut(server).unwrap().learner = learner;
             }
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.followers.is_empty()
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&FollowerState> {
+        self.followers.get(identifier)
+    }
+
+    pub fn get_mut(&mut self, identifier: &str) -> Option<&mut FollowerState> {
+        self.followers.get_mut(identifier)
     }
 
-    pub fn iter(&self) -> Values<String, FollowerState> {
-        self.0.values()
+    pub fn iter(&self) -> Values<'_, String, FollowerState> {
+        self.followers.values()
     }
 
-    pub fn iter_mut(&mut self) -> ValuesMut<String, FollowerState> {
-        self.0.values_mut()
+    pub fn iter_mut(&mut self) -> ValuesMut<'_, String, FollowerState> {
+        self.followers.values_mut()
     }
 
     pub fn add_follower(&mut self, identifier: &str, next_index: Index) {
-        self.0
+        self.followers
             .entry(String::from(identifier))
             .or_insert_with(|| FollowerState {
                 identifier: String::from(identifier),
                 next_index,
                 match_index: 0,
+                learner: false,
+                last_contact: Instant::now(),
+                acknowledged: None,
             });
     }
 
     pub fn remove_follower(&mut self, identifier: &str) {
-        self.0.remove(identifier);
+        self.followers.remove(identifier);
     }
 
-    fn others_needed_for_quorum(&self, include_self: bool) -> usize {
-        let count = self.0.len() as f32;
-        if include_self {
-            (((count + 1.0) / 2.0).ceil() - 1.0) as usize
-        } else {
-            (count / 2.0).ceil() as usize
-        }
+    fn voters(&self) -> impl Iterator<Item = &FollowerState> {
+        self.followers.values().filter(|follower| !follower.learner)
     }
 
-    pub fn meets_quorum<P>(&self, include_self: bool, mut predicate: P) -> bool
+    /// Whether this server and the followers matching `predicate` make up
+    /// a quorum.
+    pub fn meets_quorum<P>(&self, mut predicate: P) -> bool
     where
         P: FnMut(&FollowerState) -> bool,
     {
-        let quorum_size = self.others_needed_for_quorum(include_self);
-        self.0
-            .values()
-            .filter(|f| predicate(f))
-            .take(quorum_size)
-            .count()
-            == quorum_size
+        self.quorum
+            .is_met_by(|id| id == self.own_id || self.get(id).is_some_and(&mut predicate))
     }
 
-    pub async fn meets_quorum_async<P, F>(&self, include_self: bool, predicate: P) -> bool
+    /// Like [`Followers::meets_quorum`], but resolves as soon as enough
+    /// of the predicate's futures have returned true.
+    pub async fn meets_quorum_async<P, F>(&self, mut predicate: P) -> bool
     where
         P: Send + FnMut(&FollowerState) -> F,
         F: Send + Future<Output = bool>,
     {
-        let quorum_size = self.others_needed_for_quorum(include_self);
-        let fu: FuturesUnordered<_> = self.0.values().map(predicate).collect();
-        let count = fu.filter(|x| *x).take(quorum_size).count().await;
-        count == quorum_size
+        let mut agreed = HashSet::new();
+        let is_met = |agreed: &HashSet<String>| {
+            self.quorum
+                .is_met_by(|id| id == self.own_id || agreed.contains(id))
+        };
+
+        if is_met(&agreed) {
+            return true;
+        }
+
+        let mut fu: FuturesUnordered<_> = self
+            .voters()
+            .map(|follower| {
+                let identifier = follower.identifier.clone();
+                let future = predicate(follower);
+                async move { (identifier, future.await) }
+            })
+            .collect();
+
+        while let Some((identifier, agrees)) = fu.next().await {
+            if agrees {
+                agreed.insert(identifier);
+                if is_met(&agreed) {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    pub fn quorum_contacted_within(&self, duration: Duration) -> bool {
+        self.meets_quorum(|follower| follower.last_contact.elapsed() < duration)
+    }
+
+    /// Whether a quorum has acknowledged a request sent within `duration`,
+    /// in which case none of them will have voted for another leader.
+    pub fn holds_lease(&self, duration: Duration) -> bool {
+        self.meets_quorum(|follower| {
+            follower
+                .acknowledged
+                .is_some_and(|sent| sent.elapsed() < duration)
+        })
     }
 
     pub fn quorum_has_item_at_index(&self, n: Index) -> bool {
-        self.meets_quorum(true, |follower| follower.has_item_at_index(n))
+        self.meets_quorum(|follower| follower.has_item_at_index(n))
     }
 }
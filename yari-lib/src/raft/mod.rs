@@ -1,7 +1,13 @@
+mod catch_up;
+mod election;
 mod election_thread;
 mod followers;
-mod message;
+mod proposals;
+mod reads;
+mod replication;
 mod servers;
+mod sessions;
+mod transfer;
 
 pub use crate::log::LogEntry;
 pub use crate::state_machine::*;
@@ -9,28 +15,62 @@ use crate::{
     config::Config,
     log::Log,
     message_board::MessageBoard,
-    persistence,
-    rpc::{AppendRequest, AppendResponse, RaftClient, VoteRequest, VoteResponse},
+    rpc::{
+        AppendRequest, AppendResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+        MembersResponse, RaftClient, VoteRequest, VoteResponse,
+    },
+    sse_channel::{SSEChannel, Watcher},
+    storage::{
+        HardState, LogStore, MemoryLogStore, MemoryStableStore, SnapshotMeta, SnapshotWriter,
+        StableStore,
+    },
+    Result,
 };
 use async_channel::{Receiver, Sender};
+use async_lock::RwLock;
+pub use catch_up::CatchUpResult;
 pub use election_thread::ElectionThread;
 pub use followers::{FollowerState, Followers};
-use serde::{Deserialize, Serialize};
-pub use servers::{RaftMessage, Servers};
-use std::{path::PathBuf, time::Duration};
+use futures_lite::{AsyncWrite, AsyncWriteExt};
+pub use proposals::propose;
+pub use reads::{query, read_index};
+use serde::Serialize;
+pub(crate) use servers::LegacyServerConfigChange;
+pub use servers::{Quorum, RaftMessage, ServerConfigChange, Servers};
+pub use sessions::{ClientSession, SessionMessage};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+pub use transfer::TransferRefused;
 
-pub type DynBoxedResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+pub type DynBoxedResult<T = ()> = std::result::Result<T, Box<dyn std::error::Error>>;
 pub type Term = u64;
 pub type Index = usize;
 
-use RaftMessage::{Blank, ServerConfigChange, StateMachineMessage};
+type ArcRaft<SM, L, S> = Arc<
+    RwLock<
+        RaftState<SM, <SM as StateMachine>::MessageType, <SM as StateMachine>::ApplyResult, L, S>,
+    >,
+>;
+
+/// The most snapshot data sent in a single install_snapshot request.
+pub const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+use RaftMessage::{Blank, StateMachineMessage};
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct TermIndex(pub Term, pub Index);
 
+/// The result of applying a client's entry, or why it can't be known.
+pub type ProposalResult<AR> = std::result::Result<AR, String>;
+
 pub enum ElectionResult {
     Elected,
     FailedQuorum,
+    FailedPreVote,
     Ineligible,
 }
 
@@ -59,24 +99,45 @@ impl Default for InterruptChannel {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct RaftState<SM, MT, AR> {
+/// A snapshot being received from the leader, one chunk at a time.
+struct IncomingSnapshot {
+    writer: SnapshotWriter,
+    offset: u64,
+}
+
+impl Debug for IncomingSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IncomingSnapshot")
+            .field("offset", &self.offset)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(bound(serialize = "MT: Message, L: LogStore<RaftMessage<MT>>"))]
+pub struct RaftState<SM, MT, AR, L = MemoryLogStore<RaftMessage<MT>>, S = MemoryStableStore> {
     id: String,
-    log: Log<RaftMessage<MT>>,
+    log: Log<RaftMessage<MT>, L>,
     current_term: Term,
     voted_for: Option<String>,
 
+    /// Whether this server is standing in the current term's election.
+    /// It keeps its vote for itself after the election, whether it wins
+    /// or not, so that it never votes twice in one term.
     #[serde(skip)]
-    client: RaftClient<SM>,
+    candidate: bool,
 
     #[serde(skip)]
-    statefile_path: PathBuf,
+    stable_store: S,
+
+    #[serde(skip)]
+    client: RaftClient<SM>,
 
     #[serde(skip)]
     state_machine: SM,
 
     #[serde(skip)]
-    message_board: MessageBoard<TermIndex, AR>,
+    message_board: MessageBoard<TermIndex, ProposalResult<AR>>,
 
     #[serde(skip)]
     interrupt_channel: InterruptChannel,
@@ -87,65 +148,197 @@ pub struct RaftState<SM, MT, AR> {
     #[serde(skip)]
     last_applied_index: Index,
 
-    #[serde(skip_deserializing)]
     follower_state: Option<Followers>,
 
     #[serde(skip)]
     config: Config,
 
-    #[serde(skip_deserializing)]
     servers: Servers,
 
+    #[serde(skip)]
+    applied_config: Option<ServerConfigChange>,
+
+    /// The log indexes of the latest and the latest applied config
+    /// changes, unless they came from a snapshot.
+    #[serde(skip)]
+    config_index: Option<Index>,
+    #[serde(skip)]
+    applied_config_index: Option<Index>,
+
+    #[serde(skip)]
+    incoming_snapshot: Option<IncomingSnapshot>,
+
     #[serde(skip)]
     immediate_commit_index: Index,
 
     #[serde(skip)]
     leader_id_for_client_redirection: Option<String>,
-    // #[serde(skip)]
-    // channel: SSEChannel,
+
+    #[serde(skip)]
+    last_leader_contact: Option<Instant>,
+
+    #[serde(skip)]
+    transfer: Option<transfer::LeadershipTransfer>,
+
+    /// The term in which this server last sent TimeoutNow. The target may
+    /// be elected even after the transfer has given up on it.
+    #[serde(skip)]
+    timeout_now_term: Option<Term>,
+
+    #[serde(skip)]
+    election_requested: bool,
+
+    #[serde(skip)]
+    catching_up: HashMap<String, catch_up::CatchUp>,
+
+    #[serde(skip)]
+    pending_reads: Vec<reads::PendingRead>,
+
+    #[serde(skip)]
+    proposals: proposals::ProposalQueue<MT, AR>,
+
+    #[serde(skip)]
+    appended: replication::AppendSignal,
+
+    #[serde(skip)]
+    sessions: sessions::Sessions<AR>,
+
+    /// The index of the latest entry this server appended to expire
+    /// sessions.
+    #[serde(skip)]
+    session_expiry_index: Option<Index>,
+
+    #[serde(skip)]
+    channel: SSEChannel<MT>,
 }
 
-impl<SM: StateMachine> Default for RaftState<SM, SM::MessageType, SM::ApplyResult> {
+impl<SM, L, S> Default for RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>> + Default,
+    S: StableStore + Default,
+{
     fn default() -> Self {
+        Self::from_parts(
+            EphemeralState::default(),
+            L::default(),
+            S::default(),
+            HardState::default(),
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct EphemeralState<SM: StateMachine> {
+    pub id: String,
+    pub state_machine: SM,
+    pub config: Config,
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    pub async fn new(eph: EphemeralState<SM>, log_store: L, stable_store: S) -> Result<Self> {
+        let hard_state = stable_store.hard_state().await?;
+        let mut raft_state = Self::from_parts(eph, log_store, stable_store, hard_state);
+        raft_state.restore_snapshot().await?;
+        Ok(raft_state)
+    }
+
+    /// Loads the stored snapshot, if any, into the state machine, and
+    /// discards the log entries it covers.
+    async fn restore_snapshot(&mut self) -> Result<()> {
+        let Some(meta) = self.stable_store.snapshot_meta().cloned() else {
+            return Ok(());
+        };
+
+        if let Some(mut reader) = self.stable_store.snapshot_reader().await? {
+            self.state_machine.restore(&mut reader).await?;
+        }
+
+        if let Some(config) = &meta.config {
+            self.servers.visit(config);
+        }
+        self.sessions = sessions::Sessions::from_bytes(&meta.sessions)?;
+
+        let index = meta.last_included_index;
+        self.log
+            .compact(TermIndex(meta.last_included_term, index))
+            .await?;
+        self.applied_config = meta.config;
+        self.config_index = None;
+        self.applied_config_index = None;
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied_index = index;
+        self.immediate_commit_index = index;
+        self.channel.forget_through(index);
+        Ok(())
+    }
+
+    fn from_parts(
+        eph: EphemeralState<SM>,
+        log_store: L,
+        stable_store: S,
+        hard_state: HardState,
+    ) -> Self {
         Self {
-            id: String::default(),
-            log: Log::default(),
-            current_term: Term::default(),
-            voted_for: None,
-            statefile_path: PathBuf::default(),
-            state_machine: SM::default(),
+            id: eph.id,
+            log: Log::new(log_store),
+            current_term: hard_state.current_term,
+            voted_for: hard_state.voted_for,
+            candidate: false,
+            stable_store,
+            state_machine: eph.state_machine,
             interrupt_channel: InterruptChannel::default(),
             commit_index: Index::default(),
             last_applied_index: Index::default(),
             follower_state: None,
-            config: Config::default(),
+            config: eph.config,
             servers: Servers::default(),
+            applied_config: None,
+            config_index: None,
+            applied_config_index: None,
+            incoming_snapshot: None,
             client: RaftClient::new(),
             immediate_commit_index: Index::default(),
             leader_id_for_client_redirection: None,
+            last_leader_contact: None,
+            transfer: None,
+            timeout_now_term: None,
+            election_requested: false,
+            catching_up: HashMap::new(),
+            pending_reads: Vec::new(),
+            proposals: proposals::ProposalQueue::default(),
+            appended: replication::AppendSignal::default(),
+            sessions: sessions::Sessions::default(),
+            session_expiry_index: None,
             message_board: MessageBoard::default(),
-            // channel: SSEChannel::default(),
+            channel: SSEChannel::new(eph.config.watch_history()),
         }
     }
-}
-
-#[derive(Default)]
-pub struct EphemeralState<SM: StateMachine> {
-    pub id: String,
-    pub state_machine: SM,
-    pub config: Config,
-    pub statefile_path: PathBuf,
-}
 
-impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
     pub fn with_ephemeral_state(mut self, eph: EphemeralState<SM>) -> Self {
         self.id = eph.id;
-        self.statefile_path = eph.statefile_path;
         self.config = eph.config;
         self.state_machine = eph.state_machine;
         self
     }
 
+    pub fn current_term(&self) -> Term {
+        self.current_term
+    }
+
+    pub fn voted_for(&self) -> Option<&str> {
+        self.voted_for.as_deref()
+    }
+
+    pub fn log(&self) -> &Log<RaftMessage<SM::MessageType>, L> {
+        &self.log
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -158,67 +351,229 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
         self.leader_id_for_client_redirection = id;
     }
 
-    // pub fn channel(&self) -> &SSEChannel {
-    //     &self.channel
-    // }
+    pub fn channel(&self) -> &SSEChannel<SM::MessageType> {
+        &self.channel
+    }
 
-    // pub fn set_channel(&mut self, channel: SSEChannel) {
-    //     self.channel = channel;
-    // }
+    /// The messages applied from `from_index` on, or from the next one
+    /// to be applied, that the state machine says `filter` watches.
+    /// Returns the oldest index that can be watched from if `from_index`
+    /// is older than the channel's history.
+    pub fn watch(
+        &self,
+        from_index: Option<Index>,
+        filter: Option<String>,
+    ) -> std::result::Result<Watcher<SM::MessageType>, Index> {
+        let from_index = from_index.unwrap_or(self.last_applied_index + 1);
+        match filter {
+            Some(filter) => self
+                .channel
+                .watch(from_index, move |message| SM::watches(&filter, message)),
+            None => self.channel.watch(from_index, |_| true),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
 
     pub fn client(&self) -> RaftClient<SM> {
         self.client.clone()
     }
 
-    pub fn statefile_path(&self) -> &PathBuf {
-        &self.statefile_path
+    pub async fn client_append(
+        &mut self,
+        message: RaftMessage<SM::MessageType>,
+    ) -> Result<TermIndex> {
+        let term_index = self.log.client_append(self.current_term, message).await?;
+        // so that a configuration change is seen by the next one
+        self.visit_appended().await?;
+        self.appended.notify();
+        Ok(term_index)
     }
 
-    pub fn set_statefile_path(&mut self, statefile_path: PathBuf) {
-        self.statefile_path = statefile_path;
+    /// Writes a snapshot of the state machine to `writer`, returning the
+    /// term and index of the last entry it reflects.
+    pub async fn snapshot(
+        &self,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<TermIndex> {
+        self.state_machine.snapshot(writer).await?;
+        let term = self.log.term(self.last_applied_index).unwrap_or_default();
+        Ok(TermIndex(term, self.last_applied_index))
     }
 
-    pub fn client_append(&mut self, message: RaftMessage<SM::MessageType>) -> TermIndex {
-        self.log.client_append(self.current_term, message)
+    /// Snapshots the state machine into the stable store and discards the
+    /// log entries the snapshot covers.
+    pub async fn take_snapshot(&mut self) -> Result<()> {
+        if self.last_applied_index <= self.log.snapshot_index() {
+            return Ok(());
+        }
+
+        let mut writer = self.stable_store.snapshot_writer().await?;
+        let snapshot = self.snapshot(&mut writer).await?;
+        writer.close().await?;
+        drop(writer);
+
+        let TermIndex(last_included_term, last_included_index) = snapshot;
+        log::info!(
+            "{}: snapshot through index {}",
+            self.id,
+            last_included_index
+        );
+        self.stable_store
+            .finish_snapshot(SnapshotMeta {
+                last_included_index,
+                last_included_term,
+                config: self.applied_config.clone(),
+                sessions: self.sessions.to_bytes()?,
+            })
+            .await?;
+        self.log.compact(snapshot).await
     }
 
     pub fn receive_applied_result(
         &mut self,
         term_index: TermIndex,
-    ) -> async_broadcast::Receiver<SM::ApplyResult> {
+    ) -> async_broadcast::Receiver<ProposalResult<SM::ApplyResult>> {
         self.message_board.listen(term_index)
     }
 
-    pub fn member_add(&mut self, id: &str) {
+    /// Fails every entry still waiting to be applied, since only the
+    /// leader reports their results. They may yet be committed by the
+    /// next leader.
+    fn abandon_proposals(&mut self) {
+        self.message_board.post_where(
+            |_| true,
+            Err("leadership was lost before the entry was applied; \
+                 it may still be, so retry with the same session"
+                .into()),
+        );
+    }
+
+    /// Fails the entries waiting to be applied that are no longer in the
+    /// log, since they will never be committed.
+    fn abandon_overwritten_proposals(&mut self) {
+        let log = &self.log;
+        self.message_board.post_where(
+            |&TermIndex(term, index)| log.term(index) != Some(term),
+            Err("the entry was overwritten before it was committed, retry".into()),
+        );
+    }
+
+    /// Whether a membership change has yet to finish: either it is part
+    /// way through joint consensus, or its last entry isn't committed.
+    /// Only one change may be made at a time.
+    pub fn is_changing_membership(&self) -> bool {
+        self.servers.is_changing() || self.config_index > self.applied_config_index
+    }
+
+    /// Adds `id` as a voter, returning false if another change is in
+    /// progress.
+    pub async fn member_add(&mut self, id: &str) -> Result<bool> {
         log::info!("about to add member: {}", id);
-        if let Some(message) = self.servers.member_add(id) {
-            log::info!("server config change: {:?}", message);
-            self.client_append(message.into());
+        if self.is_changing_membership() {
+            return Ok(false);
+        }
+
+        match self.servers.member_add(id) {
+            Some(message) => {
+                log::info!("server config change: {:?}", message);
+                self.client_append(message.into()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
     }
 
-    pub fn member_remove(&mut self, id: &str) {
-        if let Some(message) = self.servers.member_remove(id) {
-            self.client_append(message.into());
+    /// Removes the voter or learner `id`, returning false if another
+    /// change is in progress.
+    pub async fn member_remove(&mut self, id: &str) -> Result<bool> {
+        if self.is_changing_membership() {
+            return Ok(false);
+        }
+
+        match self.servers.member_remove(id) {
+            Some(message) => {
+                self.client_append(message.into()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The committed and pending configurations, and each member's role.
+    pub fn members(&self) -> MembersResponse {
+        let leader = if self.is_leader() {
+            Some(self.id.as_str())
+        } else {
+            self.leader_id_for_client_redirection()
+        };
+
+        MembersResponse {
+            committed: self
+                .applied_config
+                .as_ref()
+                .map(|config| config.membership(self.applied_config_index)),
+            pending: (self.config_index > self.applied_config_index)
+                .then(|| self.servers.membership(self.config_index)),
+            members: self.servers.members(leader),
         }
     }
 
-    pub fn bootstrap(&mut self) {
+    /// Adds `id` as a learner, returning false if it is already a member
+    /// or another change is in progress.
+    pub async fn learner_add(&mut self, id: &str) -> Result<bool> {
+        if self.is_changing_membership() {
+            return Ok(false);
+        }
+
+        match self.servers.learner_add(id) {
+            Some(message) => {
+                log::info!("server config change: {:?}", message);
+                self.client_append(message.into()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Makes the learner `id` a voter, returning false if it is not a
+    /// learner or another change is in progress.
+    pub async fn learner_promote(&mut self, id: &str) -> Result<bool> {
+        if self.is_changing_membership() {
+            return Ok(false);
+        }
+
+        match self.servers.learner_promote(id) {
+            Some(message) => {
+                log::info!("server config change: {:?}", message);
+                self.client_append(message.into()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub async fn bootstrap(&mut self) -> Result<()> {
         if let Some(message) = self.servers.member_add(&self.id) {
-            self.client_append(message.into());
+            self.client_append(message.into()).await?;
         }
+        Ok(())
     }
 
     fn become_follower(&mut self) {
+        self.end_transfer(false);
+        self.abandon_catch_ups();
+        self.abandon_pending_reads();
+        self.abandon_proposals();
         self.follower_state = None;
         self.leader_id_for_client_redirection = None;
-        if self.is_candidate() {
-            self.voted_for = None;
-        }
+        self.candidate = false;
     }
 
     fn is_candidate(&self) -> bool {
-        self.voted_for.as_deref() == Some(&self.id)
+        self.candidate
     }
 
     pub fn is_leader(&self) -> bool {
@@ -245,68 +600,134 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
         }
     }
 
-    pub async fn commit(&mut self) {
-        log::info!("commit");
-        //        self.interrupt_timer().await;
+    /// Visits every entry appended since the last visit. A configuration
+    /// takes effect as soon as it is in the log, committed or not.
+    async fn visit_appended(&mut self) -> Result<()> {
         if let Some(entries) = self
             .log
             .entries_starting_at(self.immediate_commit_index + 1)
+            .await?
         {
             for entry in entries {
                 match &entry.message {
-                    ServerConfigChange(message) => self.servers.visit(message),
-                    StateMachineMessage(message) => self.state_machine.visit(message),
-                    Blank => (),
+                    RaftMessage::ServerConfigChange(message) => {
+                        self.servers.visit(message);
+                        self.config_index = Some(entry.index);
+                    }
+                    StateMachineMessage(message)
+                    | RaftMessage::SessionMessage(SessionMessage { message, .. }) => {
+                        self.state_machine.visit(message)
+                    }
+                    Blank | RaftMessage::ExpireSessions(_) => (),
                 }
 
                 self.immediate_commit_index = entry.index;
             }
         }
+        Ok(())
+    }
+
+    pub async fn commit(&mut self) -> Result<()> {
+        log::info!("commit");
+        //        self.interrupt_timer().await;
+        self.visit_appended().await?;
 
         while self.commit_index > self.last_applied_index {
             let next_to_apply = self.last_applied_index + 1;
-            let log_entry = self.log.get(next_to_apply).unwrap();
-            let term_index = log_entry.into();
+            let log_entry = self.log.get(next_to_apply).await?.unwrap();
+            let term_index = (&log_entry).into();
             match &log_entry.message {
-                ServerConfigChange(message) => {
+                RaftMessage::ServerConfigChange(message) => {
                     self.servers.apply(message);
+                    self.applied_config = Some(message.clone());
+                    self.applied_config_index = Some(next_to_apply);
                 }
 
                 StateMachineMessage(message) => {
                     let apply_result = self.state_machine.apply(message);
+                    self.channel.send(next_to_apply, message, &apply_result);
+                    if self.is_leader() {
+                        // entries replayed from the statefile have no listener
+                        let _ = self.message_board.post(&term_index, Ok(apply_result)).await;
+                    }
+                }
+
+                RaftMessage::SessionMessage(SessionMessage { session, message }) => {
+                    let state_machine = &mut self.state_machine;
+                    let mut applied = false;
+                    let result = self.sessions.apply(session, next_to_apply, || {
+                        applied = true;
+                        state_machine.apply(message)
+                    });
+                    // a retried command changes nothing, so watchers aren't sent it again
+                    if let (true, Ok(apply_result)) = (applied, &result) {
+                        self.channel.send(next_to_apply, message, apply_result);
+                    }
                     if self.is_leader() {
-                        self.message_board
-                            .post(&term_index, apply_result)
-                            .await
-                            .unwrap();
+                        let _ = self.message_board.post(&term_index, result).await;
                     }
                 }
 
+                RaftMessage::ExpireSessions(client_ids) => self.sessions.expire(
+                    client_ids,
+                    next_to_apply,
+                    self.config.session_max_idle_entries(),
+                ),
+
                 Blank => (),
             }
 
             self.last_applied_index = next_to_apply;
         }
+        self.notify_pending_reads();
+
+        let applied_since_snapshot = self.last_applied_index - self.log.snapshot_index();
+        if self
+            .config
+            .snapshot_due(applied_since_snapshot, self.log.bytes_since_snapshot())
+        {
+            self.take_snapshot().await?;
+        }
 
         if let Some(followers) = self.follower_state.as_mut() {
             followers.update_from_servers(&self.servers, &self.id, self.log.next_index());
             if let Some(message) = self.servers.new_config.take() {
-                self.client_append(message.into());
+                self.client_append(message.into()).await?;
             }
         }
+
+        Ok(())
+    }
+
+    async fn persist(&mut self) -> Result<()> {
+        let hard_state = HardState {
+            current_term: self.current_term,
+            voted_for: self.voted_for.clone(),
+        };
+        self.stable_store.save_hard_state(&hard_state).await
     }
 
-    async fn apply_rules(&mut self, request_term: Term) -> DynBoxedResult {
+    async fn apply_rules(&mut self, request_term: Term) -> Result<()> {
         log::info!("apply rules");
+        self.update_term(request_term);
+        self.commit().await?;
+        self.persist().await
+    }
+
+    /// Moves to `request_term` if it is later than the current term,
+    /// which starts it without a vote or a leader.
+    fn update_term(&mut self, request_term: Term) {
         if request_term > self.current_term {
+            // a transfer ends when the target's election reaches this server
+            self.end_transfer(true);
+            self.abandon_catch_ups();
+            self.abandon_pending_reads();
+            self.abandon_proposals();
             self.voted_for = None;
+            self.candidate = false;
             self.follower_state = None;
             self.current_term = request_term;
         }
-
-        self.commit().await;
-        persistence::persist(self).await?;
-        Ok(())
     }
 
     async fn interrupt(&self) {
@@ -325,7 +746,7 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
     pub async fn append(
         &mut self,
         request: AppendRequest<RaftMessage<SM::MessageType>>,
-    ) -> AppendResponse {
+    ) -> Result<AppendResponse> {
         log::info!("append");
         self.interrupt().await;
         if self.is_candidate() {
@@ -336,11 +757,15 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
 
         let leader_commit = request.leader_commit_index;
         let request_term = request.term;
-        let success = if request.term >= self.current_term {
-            self.log.append(request)
+        let conflict = if request.term >= self.current_term {
+            self.last_leader_contact = Some(Instant::now());
+            let conflict = self.log.append(request).await?;
+            self.abandon_overwritten_proposals();
+            conflict
         } else {
-            false
+            None
         };
+        let success = request_term >= self.current_term && conflict.is_none();
 
         if leader_commit > self.commit_index {
             // confirm that this is correct. paper says last *new*
@@ -351,21 +776,111 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
 
         let current_term = self.current_term;
 
-        self.apply_rules(request_term).await.unwrap();
+        self.apply_rules(request_term).await?;
 
-        AppendResponse {
+        Ok(AppendResponse {
             success,
             term: current_term,
+            conflict_term: conflict.and_then(|conflict| conflict.term),
+            conflict_index: conflict.and_then(|conflict| conflict.index),
+        })
+    }
+
+    pub async fn install_snapshot(
+        &mut self,
+        request: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        self.interrupt().await;
+        let current_term = self.current_term;
+        if request.term < current_term {
+            return Ok(InstallSnapshotResponse { term: current_term });
+        }
+
+        if self.is_candidate() {
+            self.become_follower();
+        }
+        self.leader_id_for_client_redirection = Some(request.leader_id.clone());
+        self.last_leader_contact = Some(Instant::now());
+
+        if request.offset == 0 {
+            self.incoming_snapshot = Some(IncomingSnapshot {
+                writer: self.stable_store.snapshot_writer().await?,
+                offset: 0,
+            });
         }
+
+        let incoming = match self.incoming_snapshot.as_mut() {
+            Some(incoming) if incoming.offset == request.offset => incoming,
+            _ => return Err("snapshot chunk received out of order".into()),
+        };
+        incoming.writer.write_all(&request.data).await?;
+        incoming.offset += request.data.len() as u64;
+
+        if request.done {
+            let mut incoming = self.incoming_snapshot.take().unwrap();
+            incoming.writer.close().await?;
+            drop(incoming);
+
+            let last_included_index = request.last_included_index;
+            let last_included_term = request.last_included_term;
+            if last_included_index > self.last_applied_index {
+                log::info!(
+                    "{}: installing snapshot through index {}",
+                    self.id,
+                    last_included_index
+                );
+                self.stable_store
+                    .finish_snapshot(SnapshotMeta {
+                        last_included_index,
+                        last_included_term,
+                        config: request.config,
+                        sessions: request.sessions,
+                    })
+                    .await?;
+
+                // entries after the snapshot are kept only if the log
+                // agrees with it
+                if self.log.term(last_included_index) != Some(last_included_term) {
+                    self.log.truncate(0).await?;
+                }
+
+                self.restore_snapshot().await?;
+            }
+        }
+
+        self.apply_rules(request.term).await?;
+
+        Ok(InstallSnapshotResponse { term: current_term })
     }
 
-    pub async fn vote(&mut self, request: VoteRequest) -> VoteResponse {
+    pub async fn vote(&mut self, request: VoteRequest) -> Result<VoteResponse> {
+        if request.pre_vote {
+            return Ok(self.pre_vote(&request));
+        }
+
+        // a server that can't reach the leader mustn't unseat it, or the
+        // leader's lease would no longer hold
+        if self.leader_is_alive() && !request.leadership_transfer {
+            log::debug!(
+                "{}: ignoring vote request from {} while the leader is alive",
+                self.id(),
+                request.candidate_id
+            );
+            return Ok(VoteResponse {
+                term: self.current_term,
+                vote_granted: false,
+            });
+        }
+
         self.interrupt().await;
 
-        let vote_granted = request.term >= self.current_term
+        // the vote is for the request's term, so a later term has to be
+        // taken up before the vote is weighed and persisted with it
+        self.update_term(request.term);
+
+        let vote_granted = request.term == self.current_term
             && (self.voted_for.is_none() || self.voted_for == Some(request.candidate_id.clone()))
-            && (request.last_log_index >= self.log.last_index()
-                && request.last_log_term >= self.log.last_term());
+            && self.is_up_to_date(&request);
 
         if vote_granted {
             self.voted_for = Some(request.candidate_id);
@@ -377,63 +892,51 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
             );
         }
 
-        let current_term = self.current_term;
-
-        self.apply_rules(request.term).await.unwrap();
+        self.commit().await?;
+        self.persist().await?;
 
-        VoteResponse {
-            term: current_term,
+        Ok(VoteResponse {
+            term: self.current_term,
             vote_granted,
-        }
+        })
     }
 
-    async fn start_election(&mut self) -> ElectionResult {
-        if self.servers.contains(&self.id) {
-            self.current_term += 1;
-            log::trace!(
-                "{}: starting election, term: {}",
-                self.id(),
-                self.current_term
-            );
-            self.voted_for = Some(self.id.clone());
-            self.leader_id_for_client_redirection = None;
+    /// Whether the candidate's log is at least as up to date as this
+    /// server's: its last term is later, or the same with at least as
+    /// many entries (§5.4.1).
+    fn is_up_to_date(&self, request: &VoteRequest) -> bool {
+        (request.last_log_term, request.last_log_index)
+            >= (self.log.last_term(), self.log.last_index())
+    }
 
-            let followers = Followers::from_servers(&self.servers, &self.id, self.log.next_index());
+    /// Whether this server is the leader or has heard from one within the
+    /// minimum election timeout.
+    fn leader_is_alive(&self) -> bool {
+        self.is_leader()
+            || self
+                .last_leader_contact
+                .is_some_and(|contact| contact.elapsed() < self.config.min_election_timeout())
+    }
 
-            let vote_request = VoteRequest {
-                candidate_id: self.id.clone(),
-                last_log_index: self.log.last_index(),
-                last_log_term: self.log.last_term(),
-                term: self.current_term,
-            };
-
-            let include_self = self.servers.contains(&self.id);
-
-            let quorum = followers
-                .meets_quorum_async(include_self, |follower| {
-                    let client = self.client.clone();
-                    let i = follower.identifier.clone();
-                    let vr = vote_request.clone();
-                    async move {
-                        match client.request_vote(&i, &vr).await {
-                            Ok(response) => response.vote_granted,
-                            _ => false,
-                        }
-                    }
-                })
-                .await;
-
-            if quorum {
-                self.voted_for = None;
-                self.follower_state = Some(followers);
-                self.log.client_append(self.current_term, Blank);
-                self.send_appends_or_heartbeats().await;
-                ElectionResult::Elected
-            } else {
-                ElectionResult::FailedQuorum
-            }
-        } else {
-            ElectionResult::Ineligible
+    /// Whether this server would vote for the candidate in the request's
+    /// term. Unlike [`vote`](Self::vote), nothing is recorded, and a
+    /// follower that has recently heard from a leader always refuses.
+    pub fn pre_vote(&self, request: &VoteRequest) -> VoteResponse {
+        let vote_granted = !self.leader_is_alive()
+            && request.term > self.current_term
+            && self.is_up_to_date(request);
+
+        log::debug!(
+            "{}: term {} {} pre-vote for {}",
+            self.id(),
+            self.current_term,
+            if vote_granted { "granting" } else { "refusing" },
+            request.candidate_id
+        );
+
+        VoteResponse {
+            term: self.current_term,
+            vote_granted,
         }
     }
 
@@ -473,72 +976,93 @@ impl<SM: StateMachine> RaftState<SM, SM::MessageType, SM::ApplyResult> {
             }
         }
     }
+}
 
-    pub async fn send_appends_or_heartbeats(&mut self) {
-        log::trace!("send appends or heartbeats");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence;
+    use futures_lite::future::block_on;
+    use std::fs;
+
+    fn eph() -> EphemeralState<NoopStateMachine> {
+        EphemeralState {
+            id: "http://127.0.0.1:8000/".into(),
+            state_machine: NoopStateMachine,
+            config: Config::default(),
+        }
+    }
 
-        let mut step_down = false;
+    async fn open(
+        statefile_path: &std::path::Path,
+    ) -> persistence::FileRaftState<NoopStateMachine> {
+        persistence::open(eph(), statefile_path).await.unwrap()
+    }
 
-        if let Some(followers) = self.follower_state.as_mut() {
-            let mut any_change_in_match_indexes = followers.is_empty();
-            for follower in followers.iter_mut() {
-                loop {
-                    let entries_to_send = self.log.entries_starting_at(follower.next_index);
-                    let previous_entry = self.log.previous_entry_to(follower.next_index);
-
-                    let append_request = AppendRequest {
-                        term: self.current_term,
-                        entries: entries_to_send.map(|e| e.to_vec()),
-                        leader_id: self.id.clone(),
-                        previous_log_index: previous_entry.map(|e| e.index),
-                        previous_log_term: previous_entry.map(|e| e.term),
-                        leader_commit_index: self.commit_index,
-                    };
-
-                    let append_response = self
-                        .client
-                        .append(&follower.identifier, &append_request)
-                        .await;
-
-                    match append_response {
-                        Ok(AppendResponse { term, success, .. }) if success => {
-                            if term > self.current_term {
-                                step_down = true;
-                            } else if let Some(entries_sent) = append_request.entries {
-                                let last_entry_sent = entries_sent.last().unwrap();
-                                let next_index = last_entry_sent.index + 1;
-                                let match_index = last_entry_sent.index;
-                                any_change_in_match_indexes |= follower.match_index != match_index;
-                                follower.next_index = next_index;
-                                follower.match_index = match_index;
-                            }
-
-                            break;
-                        }
-
-                        Ok(AppendResponse { term, .. }) => {
-                            if term > self.current_term {
-                                step_down = true;
-                            }
-                            follower.next_index = 2.max(follower.next_index) - 1
-                        }
-
-                        Err(_) => break,
-                    }
-                }
-            }
+    fn vote_request(term: Term, candidate_id: &str) -> VoteRequest {
+        VoteRequest {
+            term,
+            candidate_id: candidate_id.into(),
+            last_log_index: None,
+            last_log_term: None,
+            pre_vote: false,
+            leadership_transfer: false,
+        }
+    }
 
-            if any_change_in_match_indexes {
-                log::info!("change in match indexes");
-                self.update_commit_index();
-                self.commit().await;
-                persistence::persist(self).await.unwrap();
-            }
+    #[test]
+    fn votes_in_a_new_term_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("yari-vote-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("8000.yari");
+
+        block_on(async {
+            let mut raft = open(&path).await;
+            let response = raft
+                .vote(vote_request(2, "http://127.0.0.1:8001/"))
+                .await
+                .unwrap();
+            assert!(response.vote_granted);
+            assert_eq!(response.term, 2);
+            drop(raft);
+
+            let mut raft = open(&path).await;
+            assert_eq!(raft.current_term(), 2);
+            assert_eq!(raft.voted_for(), Some("http://127.0.0.1:8001/"));
+            let response = raft
+                .vote(vote_request(2, "http://127.0.0.1:8002/"))
+                .await
+                .unwrap();
+            assert!(!response.vote_granted);
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            if step_down || !self.servers.contains(&self.id) {
-                log::debug!("{}: stepping down", self.id());
-                self.become_follower();
-            }
-        }
+    #[test]
+    fn a_leader_that_steps_down_keeps_its_vote() {
+        block_on(async {
+            let mut raft: RaftState<NoopStateMachine, (), ()> = RaftState::new(
+                eph(),
+                MemoryLogStore::default(),
+                MemoryStableStore::default(),
+            )
+            .await
+            .unwrap();
+            raft.bootstrap().await.unwrap();
+            let ballot = raft.start_election(false).await.unwrap().unwrap();
+            raft.finish_election(ballot, true).await.unwrap();
+            assert!(raft.is_leader());
+
+            raft.become_follower();
+            let term = raft.current_term();
+            let response = raft
+                .vote(vote_request(term, "http://127.0.0.1:8001/"))
+                .await
+                .unwrap();
+            assert!(!response.vote_granted);
+            assert_eq!(raft.voted_for(), Some("http://127.0.0.1:8000/"));
+        });
     }
 }
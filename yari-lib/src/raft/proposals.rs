@@ -0,0 +1,157 @@
+//! Leader-side batching of client proposals.
+//!
+//! Client requests don't append to the log themselves. They queue their
+//! entries, and a single task appends whatever has queued up in one
+//! write and wakes the replication tasks, so a batch goes out without
+//! waiting for the next heartbeat. Proposals that arrive while a batch
+//! is being written join the next one, and an optional linger window
+//! lets concurrent proposals share a batch even when writes are fast.
+
+use super::{ArcRaft, ProposalResult, RaftMessage, RaftState, StateMachine};
+use crate::{
+    storage::{LogStore, StableStore},
+    Result,
+};
+use async_channel::{Receiver, Sender};
+use async_io::Timer;
+
+/// Where a proposal's result will be posted once it is applied, or why
+/// it wasn't appended.
+type Accepted<AR> = std::result::Result<async_broadcast::Receiver<ProposalResult<AR>>, String>;
+
+#[derive(Debug)]
+pub(crate) struct Proposal<MT, AR> {
+    message: RaftMessage<MT>,
+    accepted: Sender<Accepted<AR>>,
+}
+
+/// Proposals waiting for the next batch.
+#[derive(Debug)]
+pub(crate) struct ProposalQueue<MT, AR> {
+    sender: Sender<Proposal<MT, AR>>,
+    receiver: Option<Receiver<Proposal<MT, AR>>>,
+}
+
+impl<MT, AR> Default for ProposalQueue<MT, AR> {
+    fn default() -> Self {
+        let (sender, receiver) = async_channel::unbounded();
+        Self {
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    pub(crate) fn take_proposal_receiver(
+        &mut self,
+    ) -> Option<Receiver<Proposal<SM::MessageType, SM::ApplyResult>>> {
+        self.proposals.receiver.take()
+    }
+
+    /// Appends a batch of proposals in one write, and commits it at once
+    /// if there are no followers to wait for.
+    async fn append_proposals(&mut self, batch: Vec<Proposal<SM::MessageType, SM::ApplyResult>>) {
+        let refusal = if !self.is_leader() {
+            Some("this server is no longer the leader, retry")
+        } else if self.is_transferring_leadership() {
+            Some("leadership is being transferred, retry")
+        } else {
+            None
+        };
+
+        if let Some(refusal) = refusal {
+            for proposal in batch {
+                let _ = proposal.accepted.try_send(Err(refusal.into()));
+            }
+            return;
+        }
+
+        let (messages, accepted): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|proposal| (proposal.message, proposal.accepted))
+            .unzip();
+
+        log::debug!("{}: appending {} proposals", self.id, messages.len());
+        match self
+            .log
+            .client_append_batch(self.current_term, messages)
+            .await
+        {
+            Ok(term_indexes) => {
+                for (term_index, accepted) in term_indexes.into_iter().zip(accepted) {
+                    let _ = accepted.try_send(Ok(self.message_board.listen(term_index)));
+                }
+            }
+
+            Err(e) => {
+                log::error!("{}: could not append proposals: {e}", self.id);
+                for accepted in accepted {
+                    let _ = accepted.try_send(Err(e.to_string()));
+                }
+                return;
+            }
+        }
+
+        self.appended.notify();
+        self.update_commit_index();
+        if let Err(e) = self.commit().await {
+            log::error!("{}: could not commit proposals: {e}", self.id);
+        }
+    }
+}
+
+/// Queues `message` for the next batch, returning a receiver for the
+/// result of applying it once it has been appended.
+pub async fn propose<SM, L, S>(
+    raft: &ArcRaft<SM, L, S>,
+    message: RaftMessage<SM::MessageType>,
+) -> Result<async_broadcast::Receiver<ProposalResult<SM::ApplyResult>>>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    let sender = raft.read().await.proposals.sender.clone();
+    let (accepted, receiver) = async_channel::bounded(1);
+    sender
+        .send(Proposal { message, accepted })
+        .await
+        .map_err(|_| "proposals are no longer being accepted")?;
+
+    match receiver.recv().await {
+        Ok(accepted) => Ok(accepted?),
+        Err(_) => Err("the proposal was dropped before it was appended".into()),
+    }
+}
+
+/// Appends queued proposals in batches until the queue is closed.
+pub(crate) async fn append_batches<SM, L, S>(raft: ArcRaft<SM, L, S>)
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    let Some(receiver) = raft.write().await.take_proposal_receiver() else {
+        return;
+    };
+
+    while let Ok(first) = receiver.recv().await {
+        let linger = raft.read().await.config.proposal_linger();
+        if !linger.is_zero() {
+            Timer::after(linger).await;
+        }
+
+        let mut batch = vec![first];
+        while let Ok(proposal) = receiver.try_recv() {
+            batch.push(proposal);
+        }
+
+        raft.write().await.append_proposals(batch).await;
+    }
+}
@@ -0,0 +1,257 @@
+//! Reads that don't go through the log.
+//!
+//! For a linearizable read, the leader notes its commit index, confirms
+//! it is still leader with a round of heartbeats (the ReadIndex
+//! protocol), and answers once the state machine has applied everything
+//! up to that index. A lease read skips the heartbeats while a quorum has
+//! recently acknowledged the leader, and a stale read is answered from
+//! whatever the server has applied.
+
+use super::{ArcRaft, Followers, RaftMessage, RaftState, StateMachine, Term};
+use crate::{
+    rpc::{AppendRequest, RaftClient, ReadConsistency, ReadIndexRequest},
+    storage::{LogStore, StableStore},
+    Index, Message, Result, TermIndex,
+};
+use async_channel::{Receiver, Sender};
+use async_io::Timer;
+use futures_lite::FutureExt;
+use std::{collections::HashMap, time::Duration};
+
+/// A read waiting for the state machine to apply `index`.
+#[derive(Debug)]
+pub(crate) struct PendingRead {
+    index: Index,
+    done: Sender<()>,
+}
+
+/// The index a read waits for, and heartbeats that confirm this server
+/// is still the leader in `term`.
+pub(crate) struct ReadIndex<MT> {
+    index: Index,
+    term: Term,
+    followers: Followers,
+    heartbeats: HashMap<String, AppendRequest<RaftMessage<MT>>>,
+}
+
+impl<MT: Message> ReadIndex<MT> {
+    /// Whether a quorum acknowledges this leader within `timeout`.
+    async fn confirm<SM: StateMachine>(&self, client: &RaftClient<SM>, timeout: Duration) -> bool {
+        let acknowledged = self.followers.meets_quorum_async(|follower| {
+            let client = client.clone();
+            let id = follower.identifier.clone();
+            let heartbeat = self.heartbeats.get(&id);
+            async move {
+                match heartbeat {
+                    Some(heartbeat) => client
+                        .append(&id, heartbeat)
+                        .await
+                        .is_ok_and(|response| response.term <= self.term),
+                    None => false,
+                }
+            }
+        });
+
+        let timeout = async {
+            Timer::after(timeout).await;
+            false
+        };
+
+        acknowledged.or(timeout).await
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    /// The index a read must wait for to see every committed write.
+    fn read_index_to_wait_for(&self) -> Index {
+        // until an entry from this term is committed, the commit index
+        // may be behind what an earlier leader committed
+        if self.log.term(self.commit_index) == Some(self.current_term) {
+            self.commit_index
+        } else {
+            self.log
+                .last_index_in_term(self.current_term)
+                .unwrap_or(self.commit_index)
+        }
+    }
+
+    /// The index a read must wait for, and the heartbeats that confirm
+    /// leadership, or `None` if this server is not the leader.
+    fn read_index(&self) -> Option<ReadIndex<SM::MessageType>> {
+        let follower_state = self.follower_state.as_ref().filter(|_| self.is_leader())?;
+        let index = self.read_index_to_wait_for();
+
+        let heartbeats = follower_state
+            .iter()
+            .map(|follower| {
+                let previous_entry = self.log.previous_entry_to(follower.next_index);
+                let heartbeat = AppendRequest {
+                    term: self.current_term,
+                    leader_id: self.id.clone(),
+                    previous_log_index: previous_entry.map(|TermIndex(_, index)| index),
+                    previous_log_term: previous_entry.map(|TermIndex(term, _)| term),
+                    entries: None,
+                    leader_commit_index: self.commit_index,
+                };
+                (follower.identifier.clone(), heartbeat)
+            })
+            .collect();
+
+        Some(ReadIndex {
+            index,
+            term: self.current_term,
+            followers: Followers::from_servers(&self.servers, &self.id, self.log.next_index()),
+            heartbeats,
+        })
+    }
+
+    /// The index a read must wait for if this server is the leader and
+    /// holds a lease, so that no heartbeats are needed.
+    ///
+    /// A TimeoutNow lets its target start an election without waiting
+    /// for the lease to expire, so no lease is trusted while a transfer
+    /// is under way or for the rest of a term in which one was sent.
+    fn lease_read_index(&self) -> Option<Index> {
+        if self.is_transferring_leadership() || self.timeout_now_term == Some(self.current_term) {
+            return None;
+        }
+
+        self.follower_state
+            .as_ref()
+            .filter(|followers| {
+                self.is_leader() && followers.holds_lease(self.config.lease_duration())
+            })
+            .map(|_| self.read_index_to_wait_for())
+    }
+
+    /// Resolves once the state machine has applied `index`.
+    fn wait_for_applied(&mut self, index: Index) -> Receiver<()> {
+        let (done, receiver) = async_channel::bounded(1);
+        if self.last_applied_index >= index {
+            let _ = done.try_send(());
+        } else {
+            self.pending_reads.push(PendingRead { index, done });
+        }
+        receiver
+    }
+
+    pub(crate) fn notify_pending_reads(&mut self) {
+        let last_applied_index = self.last_applied_index;
+        self.pending_reads.retain(|read| {
+            if read.index <= last_applied_index {
+                let _ = read.done.try_send(());
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Fails reads still waiting on entries once the term changes, since
+    /// the index they wait for may never be committed.
+    pub(crate) fn abandon_pending_reads(&mut self) {
+        self.pending_reads.clear();
+    }
+}
+
+/// Answers `query` from state at least as up to date as `consistency`
+/// requires, along with the index of the last entry applied to it. A
+/// follower asks the leader which index it must wait for.
+pub async fn query<SM, L, S>(
+    raft: &ArcRaft<SM, L, S>,
+    query: &SM::QueryType,
+    consistency: ReadConsistency,
+) -> Result<(SM::ApplyResult, Index)>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    if consistency != ReadConsistency::Stale {
+        let is_leader = raft.read().await.is_leader();
+        let index = if is_leader {
+            read_index(raft, consistency).await?
+        } else {
+            leader_read_index(raft, consistency).await?
+        };
+
+        let applied = raft.write().await.wait_for_applied(index);
+        applied
+            .recv()
+            .await
+            .map_err(|_| "the term changed before the read could be answered")?;
+    }
+
+    let raft = raft.read().await;
+    Ok((raft.state_machine.query(query), raft.last_applied_index))
+}
+
+/// The index a read with `consistency` must wait for, if this server is
+/// the leader.
+pub async fn read_index<SM, L, S>(
+    raft: &ArcRaft<SM, L, S>,
+    consistency: ReadConsistency,
+) -> Result<Index>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    if consistency == ReadConsistency::Lease {
+        if let Some(index) = raft.read().await.lease_read_index() {
+            return Ok(index);
+        }
+    }
+
+    let (read_index, client, timeout) = {
+        let raft = raft.read().await;
+        let read_index = raft.read_index().ok_or("not the leader")?;
+        (
+            read_index,
+            raft.client(),
+            raft.config.max_election_timeout(),
+        )
+    };
+
+    if read_index.confirm(&client, timeout).await {
+        Ok(read_index.index)
+    } else {
+        Err("could not confirm leadership with a quorum".into())
+    }
+}
+
+/// Asks the leader for the index a read must wait for.
+async fn leader_read_index<SM, L, S>(
+    raft: &ArcRaft<SM, L, S>,
+    consistency: ReadConsistency,
+) -> Result<Index>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    let (leader, client, timeout) = {
+        let raft = raft.read().await;
+        let leader = raft
+            .leader_id_for_client_redirection()
+            .ok_or("no leader is known")?
+            .to_string();
+        (leader, raft.client(), raft.config.max_election_timeout())
+    };
+
+    let request = ReadIndexRequest {
+        read_consistency: consistency,
+    };
+    let response = async { Ok(client.read_index(&leader, &request).await?.index) };
+    let timeout = async {
+        Timer::after(timeout).await;
+        Err(format!("{leader} did not answer in time").into())
+    };
+
+    response.or(timeout).await
+}
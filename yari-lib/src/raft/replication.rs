@@ -0,0 +1,459 @@
+//! Leader-side replication.
+//!
+//! Each follower is served by its own task, so a slow or unreachable
+//! follower only delays itself. A task holds the raft lock just long
+//! enough to prepare a request and, once the follower replies, to record
+//! the result. The network round trip happens without it. Tasks send
+//! new entries as soon as they are appended, and otherwise send a
+//! heartbeat every interval.
+
+use super::{ArcRaft, RaftMessage, RaftState, StateMachine, Term, SNAPSHOT_CHUNK_SIZE};
+use crate::{
+    rpc::{
+        AppendRequest, AppendResponse, InstallSnapshotRequest, InstallSnapshotResponse, RaftClient,
+    },
+    storage::{LogStore, SnapshotMeta, SnapshotReader, StableStore},
+    Index, Result, TermIndex,
+};
+use async_global_executor::Task;
+use async_io::Timer;
+use futures_lite::{AsyncReadExt, FutureExt};
+use std::{collections::HashMap, ops::Range, time::Instant};
+use unicycle::FuturesUnordered;
+
+/// The next request for a follower.
+pub(crate) enum Replication<MT> {
+    Append(AppendRequest<RaftMessage<MT>>),
+    Snapshot(SnapshotMeta, SnapshotReader),
+}
+
+/// A follower's reply to a [`Replication`], along with the indexes it
+/// covered and when it was sent.
+pub(crate) enum Reply {
+    Append(AppendResponse, Range<Index>, Instant),
+    Snapshot(InstallSnapshotResponse, Index, Instant),
+}
+
+/// Wakes the replication tasks when entries are appended. Signals that
+/// a task hasn't seen yet are merged into one.
+#[derive(Debug)]
+pub(crate) struct AppendSignal {
+    sender: async_broadcast::Sender<()>,
+    // keeps the channel open while there are no followers
+    _inactive: async_broadcast::InactiveReceiver<()>,
+}
+
+impl Default for AppendSignal {
+    fn default() -> Self {
+        let (mut sender, receiver) = async_broadcast::broadcast(1);
+        sender.set_overflow(true);
+        Self {
+            sender,
+            _inactive: receiver.deactivate(),
+        }
+    }
+}
+
+impl AppendSignal {
+    pub(crate) fn notify(&self) {
+        let _ = self.sender.try_broadcast(());
+    }
+
+    fn subscribe(&self) -> async_broadcast::Receiver<()> {
+        self.sender.new_receiver()
+    }
+}
+
+/// What a replication task woke up for.
+enum Wake {
+    Heartbeat,
+    Appended,
+    Reply(Result<Reply>),
+}
+
+/// The running replication tasks, one per follower, for a single term.
+#[derive(Default)]
+pub(crate) struct Replicators {
+    term: Term,
+    tasks: HashMap<String, Task<()>>,
+}
+
+impl Replicators {
+    /// Starts a task for each new follower and cancels those for
+    /// followers that have been removed.
+    pub(crate) fn update<SM, L, S>(
+        &mut self,
+        raft: &ArcRaft<SM, L, S>,
+        term: Term,
+        followers: Vec<String>,
+    ) where
+        SM: StateMachine,
+        L: LogStore<RaftMessage<SM::MessageType>>,
+        S: StableStore,
+    {
+        if term != self.term {
+            self.clear();
+            self.term = term;
+        }
+
+        self.tasks.retain(|id, _| followers.contains(id));
+        for follower in followers {
+            self.tasks.entry(follower.clone()).or_insert_with(|| {
+                async_global_executor::spawn(replicate(raft.clone(), follower, term))
+            });
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.tasks.clear();
+    }
+}
+
+async fn replicate<SM, L, S>(raft: ArcRaft<SM, L, S>, follower: String, term: Term)
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    let (client, leader_id, config, mut appended) = {
+        let raft = raft.read().await;
+        (
+            raft.client(),
+            raft.id.clone(),
+            raft.config,
+            raft.appended.subscribe(),
+        )
+    };
+
+    let mut in_flight = FuturesUnordered::new();
+    // where the next append starts, if ahead of the follower's confirmed
+    // next index because earlier appends are still in flight
+    let mut cursor = None;
+    let mut heartbeat_due = true;
+    let mut paused = false;
+    let mut snapshot_in_flight = false;
+    let mut next_heartbeat = Instant::now() + config.heartbeat_interval();
+
+    loop {
+        while !paused && !snapshot_in_flight && in_flight.len() < config.max_in_flight_appends() {
+            let prepared = raft
+                .read()
+                .await
+                .prepare_replication(&follower, term, cursor)
+                .await;
+
+            let replication = match prepared {
+                Ok(Some(replication)) => replication,
+                Ok(None) => return,
+                Err(e) => {
+                    log::error!("{leader_id}: could not prepare replication to {follower}: {e}");
+                    paused = true;
+                    break;
+                }
+            };
+
+            match &replication {
+                Replication::Append(request) if request.entries.is_none() && !heartbeat_due => {
+                    break
+                }
+                Replication::Append(request) => cursor = Some(indexes_sent(request).end),
+                Replication::Snapshot(..) if !in_flight.is_empty() => break,
+                Replication::Snapshot(..) => snapshot_in_flight = true,
+            }
+
+            heartbeat_due = false;
+            in_flight.push(send(&client, &follower, &leader_id, term, replication));
+        }
+
+        let heartbeat = async {
+            Timer::at(next_heartbeat).await;
+            Wake::Heartbeat
+        };
+        let can_send =
+            !paused && !snapshot_in_flight && in_flight.len() < config.max_in_flight_appends();
+        let new_entries = async {
+            if !can_send {
+                // there is nothing to do with new entries until a reply
+                // or the heartbeat
+                futures_lite::future::pending::<()>().await;
+            }
+            let _ = appended.recv().await;
+            Wake::Appended
+        };
+        let reply = async {
+            match in_flight.next().await {
+                Some(reply) => Wake::Reply(reply),
+                None => futures_lite::future::pending().await,
+            }
+        };
+
+        match reply.or(new_entries).or(heartbeat).await {
+            Wake::Heartbeat => {
+                next_heartbeat = Instant::now() + config.heartbeat_interval();
+                heartbeat_due = true;
+                paused = false;
+            }
+
+            Wake::Appended => {}
+
+            Wake::Reply(Ok(reply)) => {
+                let recorded = raft
+                    .write()
+                    .await
+                    .record_reply(&follower, term, reply)
+                    .await;
+
+                match recorded {
+                    Ok(false) => {}
+                    Ok(true) => cursor = None,
+                    Err(e) => {
+                        log::error!("{leader_id}: could not record reply from {follower}: {e}");
+                        cursor = None;
+                        paused = true;
+                    }
+                }
+            }
+
+            Wake::Reply(Err(e)) => {
+                log::debug!("{leader_id}: could not reach {follower}: {e}");
+                cursor = None;
+                paused = true;
+            }
+        }
+
+        if in_flight.is_empty() {
+            snapshot_in_flight = false;
+        }
+    }
+}
+
+/// The indexes covered by an append, which may be none for a heartbeat.
+fn indexes_sent<M>(request: &AppendRequest<M>) -> Range<Index> {
+    let start = request.previous_log_index.map_or(1, |index| index + 1);
+    let end = request
+        .entries
+        .as_ref()
+        .and_then(|entries| entries.last())
+        .map_or(start, |entry| entry.index + 1);
+    start..end
+}
+
+async fn send<SM: StateMachine>(
+    client: &RaftClient<SM>,
+    follower: &str,
+    leader_id: &str,
+    term: Term,
+    replication: Replication<SM::MessageType>,
+) -> Result<Reply> {
+    match replication {
+        Replication::Append(request) => {
+            let sent = Instant::now();
+            let response = client.append(follower, &request).await?;
+            Ok(Reply::Append(response, indexes_sent(&request), sent))
+        }
+
+        Replication::Snapshot(meta, mut reader) => {
+            log::info!(
+                "{leader_id}: sending snapshot through index {} to {follower}",
+                meta.last_included_index
+            );
+
+            let mut offset = 0;
+            loop {
+                let mut data = Vec::with_capacity(SNAPSHOT_CHUNK_SIZE);
+                (&mut reader)
+                    .take(SNAPSHOT_CHUNK_SIZE as u64)
+                    .read_to_end(&mut data)
+                    .await?;
+
+                // the sessions are only needed once the snapshot is installed
+                let done = data.len() < SNAPSHOT_CHUNK_SIZE;
+                let request = InstallSnapshotRequest {
+                    term,
+                    leader_id: leader_id.to_string(),
+                    last_included_index: meta.last_included_index,
+                    last_included_term: meta.last_included_term,
+                    config: meta.config.clone(),
+                    sessions: if done { meta.sessions.clone() } else { vec![] },
+                    offset,
+                    done,
+                    data,
+                };
+                offset += request.data.len() as u64;
+
+                let sent = Instant::now();
+                let response = client.install_snapshot(follower, &request).await?;
+                if request.done || response.term > term {
+                    return Ok(Reply::Snapshot(response, meta.last_included_index, sent));
+                }
+            }
+        }
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    /// The followers that need replication tasks.
+    pub(crate) fn follower_ids(&self) -> Vec<String> {
+        self.follower_state
+            .iter()
+            .flat_map(|followers| followers.iter())
+            .map(|follower| follower.identifier.clone())
+            .collect()
+    }
+
+    /// Commits whatever a quorum has acknowledged, and steps down if this
+    /// server has been removed from the cluster or has not heard from a
+    /// quorum within an election timeout.
+    pub(crate) async fn leader_tick(&mut self) -> Result<()> {
+        self.update_commit_index();
+        self.commit().await?;
+        self.persist().await?;
+
+        if self.is_leader() && !self.servers.is_voter(&self.id) {
+            log::debug!("{}: stepping down", self.id());
+            self.become_follower();
+        }
+
+        self.check_transfer_deadline();
+        self.check_catch_up().await?;
+        self.expire_sessions().await?;
+
+        let timeout = self.config.max_election_timeout();
+        if let Some(followers) = &self.follower_state {
+            if !followers.quorum_contacted_within(timeout) {
+                log::info!("{}: lost contact with a quorum, stepping down", self.id());
+                self.become_follower();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the next request for `follower`, starting at `from` or else
+    /// at its next index, or returns `None` if this server is no longer
+    /// its leader in `term`.
+    async fn prepare_replication(
+        &self,
+        follower: &str,
+        term: Term,
+        from: Option<Index>,
+    ) -> Result<Option<Replication<SM::MessageType>>> {
+        let follower_state = self
+            .follower_state
+            .as_ref()
+            .and_then(|followers| followers.get(follower));
+
+        let next_index = match follower_state {
+            Some(follower_state) if self.current_term == term => {
+                from.unwrap_or(follower_state.next_index)
+            }
+            _ => return Ok(None),
+        };
+
+        if next_index <= self.log.snapshot_index() {
+            if let (Some(meta), Some(reader)) = (
+                self.stable_store.snapshot_meta(),
+                self.stable_store.snapshot_reader().await?,
+            ) {
+                return Ok(Some(Replication::Snapshot(meta.clone(), reader)));
+            }
+        }
+
+        let entries = self
+            .log
+            .batch_starting_at(
+                next_index,
+                self.config.max_entries_per_append(),
+                self.config.max_bytes_per_append(),
+            )
+            .await?;
+        let previous_entry = self.log.previous_entry_to(next_index);
+
+        Ok(Some(Replication::Append(AppendRequest {
+            term,
+            entries,
+            leader_id: self.id.clone(),
+            previous_log_index: previous_entry.map(|TermIndex(_, index)| index),
+            previous_log_term: previous_entry.map(|TermIndex(term, _)| term),
+            leader_commit_index: self.commit_index,
+        })))
+    }
+
+    /// Records a reply from `follower`, returning whether replication
+    /// should resume from its confirmed next index rather than from where
+    /// the last append in flight left off.
+    async fn record_reply(&mut self, follower: &str, term: Term, reply: Reply) -> Result<bool> {
+        let (reply_term, sent) = match &reply {
+            Reply::Append(response, _, sent) => (response.term, *sent),
+            Reply::Snapshot(response, _, sent) => (response.term, *sent),
+        };
+
+        if reply_term > self.current_term {
+            log::debug!("{}: stepping down", self.id());
+            self.apply_rules(reply_term).await?;
+            return Ok(true);
+        }
+
+        let Some(follower_state) = self
+            .follower_state
+            .as_mut()
+            .and_then(|followers| followers.get_mut(follower))
+            .filter(|_| self.current_term == term)
+        else {
+            return Ok(true);
+        };
+
+        follower_state.last_contact = Instant::now();
+        follower_state.acknowledged = follower_state.acknowledged.max(Some(sent));
+
+        let (match_index, restart) = match reply {
+            Reply::Append(AppendResponse { success: true, .. }, sent, _) => (sent.end - 1, false),
+
+            Reply::Append(
+                AppendResponse {
+                    conflict_term,
+                    conflict_index,
+                    ..
+                },
+                sent,
+                _,
+            ) => {
+                let next_index = match (conflict_term, conflict_index) {
+                    // skip every entry in the conflicting term, unless
+                    // this log has entries in it too
+                    (Some(term), Some(index)) => self
+                        .log
+                        .last_index_in_term(term)
+                        .map_or(index, |last| last + 1),
+                    (None, last_index) => last_index.map_or(1, |last| last + 1),
+                    (Some(_), None) => sent.start - 1,
+                };
+
+                // replies to earlier appends may arrive after later ones,
+                // so nothing the follower has acknowledged is resent
+                follower_state.next_index = next_index
+                    .min(sent.start - 1)
+                    .max(follower_state.match_index + 1);
+                (0, true)
+            }
+
+            Reply::Snapshot(_, last_included_index, _) => (last_included_index, true),
+        };
+
+        follower_state.next_index = follower_state.next_index.max(match_index + 1);
+        if match_index > follower_state.match_index {
+            follower_state.match_index = match_index;
+            self.update_commit_index();
+            self.commit().await?;
+            self.persist().await?;
+            self.send_timeout_now_if_caught_up();
+        }
+
+        Ok(restart)
+    }
+}
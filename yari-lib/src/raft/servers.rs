@@ -1,11 +1,23 @@
-use crate::raft::{Message, StateMachine};
-use serde::{Deserialize, Serialize};
+use crate::{
+    raft::{Message, SessionMessage, StateMachine},
+    rpc::{Member, MemberRole, Membership},
+    Index,
+};
+use serde::{Deserialize, Serialize, Serializer};
 use std::collections::{hash_set::Iter, HashSet};
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 
+/// The cluster's members. Voters count toward elections and commits;
+/// learners only receive the log.
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Servers {
     set: HashSet<String>,
+    #[serde(default)]
+    learners: HashSet<String>,
+    /// While a change is in flight, the old and new voters. Each needs
+    /// its own majority, and `set` holds both.
+    #[serde(default)]
+    joint: Option<(HashSet<String>, HashSet<String>)>,
     pub new_config: Option<ServerConfigChange>,
 }
 
@@ -13,16 +25,90 @@ pub struct Servers {
 pub struct ServerConfigChange {
     current: HashSet<String>,
     new: Option<HashSet<String>>,
+    #[serde(default)]
+    learners: HashSet<String>,
 }
 impl Message for ServerConfigChange {}
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-#[serde(tag = "type")]
+/// A [`ServerConfigChange`] as written before learners existed.
+#[derive(Deserialize)]
+pub(crate) struct LegacyServerConfigChange {
+    current: HashSet<String>,
+    new: Option<HashSet<String>>,
+}
+
+impl From<LegacyServerConfigChange> for ServerConfigChange {
+    fn from(LegacyServerConfigChange { current, new }: LegacyServerConfigChange) -> Self {
+        Self {
+            current,
+            new,
+            learners: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(from = "StoredRaftMessage<MT>")]
 pub enum RaftMessage<MT> {
     ServerConfigChange(ServerConfigChange),
     StateMachineMessage(MT),
     #[default]
     Blank,
+    SessionMessage(SessionMessage<MT>),
+    /// Forgets the sessions of these clients.
+    ExpireSessions(Vec<String>),
+}
+
+/// How a [`RaftMessage`] is encoded. Bincode identifies variants by
+/// position, so config changes written before learners existed keep the
+/// first variant, current ones come after those, and new variants are
+/// only ever added at the end.
+#[derive(Deserialize)]
+enum StoredRaftMessage<MT> {
+    LegacyServerConfigChange(LegacyServerConfigChange),
+    StateMachineMessage(MT),
+    Blank,
+    ServerConfigChange(ServerConfigChange),
+    SessionMessage(SessionMessage<MT>),
+    ExpireSessions(Vec<String>),
+}
+
+impl<MT> From<StoredRaftMessage<MT>> for RaftMessage<MT> {
+    fn from(stored: StoredRaftMessage<MT>) -> Self {
+        match stored {
+            StoredRaftMessage::LegacyServerConfigChange(scc) => {
+                Self::ServerConfigChange(scc.into())
+            }
+            StoredRaftMessage::ServerConfigChange(scc) => Self::ServerConfigChange(scc),
+            StoredRaftMessage::StateMachineMessage(message) => Self::StateMachineMessage(message),
+            StoredRaftMessage::Blank => Self::Blank,
+            StoredRaftMessage::SessionMessage(message) => Self::SessionMessage(message),
+            StoredRaftMessage::ExpireSessions(client_ids) => Self::ExpireSessions(client_ids),
+        }
+    }
+}
+
+impl<MT: Serialize> Serialize for RaftMessage<MT> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::ServerConfigChange(scc) => {
+                serializer.serialize_newtype_variant("RaftMessage", 3, "ServerConfigChange", scc)
+            }
+            Self::StateMachineMessage(message) => serializer.serialize_newtype_variant(
+                "RaftMessage",
+                1,
+                "StateMachineMessage",
+                message,
+            ),
+            Self::Blank => serializer.serialize_unit_variant("RaftMessage", 2, "Blank"),
+            Self::SessionMessage(message) => {
+                serializer.serialize_newtype_variant("RaftMessage", 4, "SessionMessage", message)
+            }
+            Self::ExpireSessions(client_ids) => {
+                serializer.serialize_newtype_variant("RaftMessage", 5, "ExpireSessions", client_ids)
+            }
+        }
+    }
 }
 
 impl<MT> From<ServerConfigChange> for RaftMessage<MT> {
@@ -35,40 +121,192 @@ impl<MT: Message> Message for RaftMessage<MT> {}
 
 impl Debug for ServerConfigChange {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{:?}->{:?}", self.current, self.new)
+        write!(f, "{:?}->{:?}", self.current, self.new)?;
+        if !self.learners.is_empty() {
+            write!(f, " learners: {:?}", self.learners)?;
+        }
+        Ok(())
+    }
+}
+
+impl ServerConfigChange {
+    pub fn membership(&self, index: Option<Index>) -> Membership {
+        Membership {
+            index,
+            voters: self.current.iter().cloned().collect(),
+            new_voters: self.new.as_ref().map(|new| new.iter().cloned().collect()),
+            learners: self.learners.iter().cloned().collect(),
+        }
     }
 }
 
 impl Servers {
+    /// The configuration in effect, whether or not it is committed.
+    pub fn membership(&self, index: Option<Index>) -> Membership {
+        let (voters, new_voters) = match &self.joint {
+            Some((old, new)) => (old, Some(new.iter().cloned().collect())),
+            None => (&self.set, None),
+        };
+
+        Membership {
+            index,
+            voters: voters.iter().cloned().collect(),
+            new_voters,
+            learners: self.learners.iter().cloned().collect(),
+        }
+    }
+
+    pub fn members(&self, leader: Option<&str>) -> Vec<Member> {
+        let mut members: Vec<_> = self
+            .set
+            .iter()
+            .map(|id| {
+                let role = match &self.joint {
+                    Some((old, _)) if !old.contains(id) => MemberRole::Joining,
+                    Some((_, new)) if !new.contains(id) => MemberRole::Leaving,
+                    _ => MemberRole::Voter,
+                };
+                (id, role)
+            })
+            .chain(self.learners.iter().map(|id| (id, MemberRole::Learner)))
+            .map(|(id, role)| Member {
+                id: id.clone(),
+                role,
+                leader: leader == Some(id.as_str()),
+            })
+            .collect();
+
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+
+    /// Whether a change is part way through joint consensus. No other
+    /// change may start until it has finished.
+    pub fn is_changing(&self) -> bool {
+        self.joint.is_some() || self.new_config.is_some()
+    }
+
+    /// Adds `id` as a voter, or returns `None` while another change is
+    /// in progress.
     pub fn member_add(&self, id: &str) -> Option<ServerConfigChange> {
+        if self.is_changing() {
+            return None;
+        }
+
         let mut new = self.set.clone();
         new.insert(id.into());
 
+        let mut learners = self.learners.clone();
+        learners.remove(id);
+
         Some(ServerConfigChange {
             current: self.set.clone(),
             new: Some(new),
+            learners,
         })
     }
 
+    /// Removes the voter or learner `id`, or returns `None` while another
+    /// change is in progress.
     pub fn member_remove(&self, id: &str) -> Option<ServerConfigChange> {
+        if self.is_changing() {
+            return None;
+        }
+
+        if self.learners.contains(id) {
+            let mut learners = self.learners.clone();
+            learners.remove(id);
+
+            return Some(ServerConfigChange {
+                current: self.set.clone(),
+                new: None,
+                learners,
+            });
+        }
+
         let mut new = self.set.clone();
         new.remove(id);
 
         Some(ServerConfigChange {
             current: self.set.clone(),
             new: Some(new),
+            learners: self.learners.clone(),
+        })
+    }
+
+    /// Adds `id` as a learner. Since learners don't count toward any
+    /// quorum, this takes effect without joint consensus, but it still
+    /// waits for any other change to finish.
+    pub fn learner_add(&self, id: &str) -> Option<ServerConfigChange> {
+        if self.is_changing() || self.contains(id) {
+            return None;
+        }
+
+        let mut learners = self.learners.clone();
+        learners.insert(id.into());
+
+        Some(ServerConfigChange {
+            current: self.set.clone(),
+            new: None,
+            learners,
         })
     }
 
+    /// Makes the learner `id` a voter, or returns `None` if it is not a
+    /// learner or another change is in progress.
+    pub fn learner_promote(&self, id: &str) -> Option<ServerConfigChange> {
+        if self.learners.contains(id) {
+            self.member_add(id)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `id` is a voter or a learner.
     pub fn contains(&self, id: &str) -> bool {
+        self.set.contains(id) || self.learners.contains(id)
+    }
+
+    pub fn is_voter(&self, id: &str) -> bool {
         self.set.contains(id)
     }
 
+    pub fn is_learner(&self, id: &str) -> bool {
+        self.learners.contains(id)
+    }
+
+    pub fn learners(&self) -> Iter<'_, String> {
+        self.learners.iter()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.set.is_empty()
     }
+
+    pub fn quorum(&self) -> Quorum {
+        match &self.joint {
+            Some((old, new)) => Quorum(vec![old.clone(), new.clone()]),
+            None => Quorum(vec![self.set.clone()]),
+        }
+    }
+}
+
+/// The configurations that must each agree, by majority, for a decision
+/// to be made: just the current one, or both the old and new ones during
+/// a change.
+#[derive(Debug, Default, Clone)]
+pub struct Quorum(Vec<HashSet<String>>);
+
+impl Quorum {
+    pub fn is_met_by<F: FnMut(&str) -> bool>(&self, mut agrees: F) -> bool {
+        self.0
+            .iter()
+            .filter(|voters| !voters.is_empty())
+            .all(|voters| voters.iter().filter(|id| agrees(id)).count() * 2 > voters.len())
+    }
 }
 
+/// Iterates over the voters.
 impl<'a> IntoIterator for &'a Servers {
     type Item = &'a String;
     type IntoIter = Iter<'a, String>;
@@ -81,19 +319,106 @@ impl<'a> IntoIterator for &'a Servers {
 impl StateMachine for Servers {
     type MessageType = ServerConfigChange;
     type ApplyResult = ();
+    type QueryType = ();
 
     fn apply(&mut self, scc: &ServerConfigChange) {
         self.new_config = scc.new.as_ref().map(|new| ServerConfigChange {
             current: new.clone(),
             new: None,
+            learners: scc.learners.clone(),
         });
     }
 
     fn visit(&mut self, scc: &ServerConfigChange) {
-        self.set = if let Some(new) = &scc.new {
-            scc.current.union(new).cloned().collect()
+        if let Some(new) = &scc.new {
+            self.set = scc.current.union(new).cloned().collect();
+            self.joint = Some((scc.current.clone(), new.clone()));
         } else {
-            scc.current.clone()
-        };
+            self.set = scc.current.clone();
+            self.joint = None;
+        }
+        self.learners = scc.learners.clone();
+    }
+
+    fn type_tag() -> &'static str {
+        "yari::raft::servers::Servers"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn joint(old: &[&str], new: &[&str]) -> Quorum {
+        Quorum(vec![set(old), set(new)])
+    }
+
+    fn votes<'a>(ids: &'a [&str]) -> impl FnMut(&str) -> bool + 'a {
+        move |id| ids.contains(&id)
+    }
+
+    #[test]
+    fn a_single_configuration_needs_a_majority() {
+        let quorum = Quorum(vec![set(&["a", "b", "c", "d"])]);
+        assert!(!quorum.is_met_by(votes(&["a", "b"])));
+        assert!(quorum.is_met_by(votes(&["a", "b", "c"])));
+        assert!(!quorum.is_met_by(votes(&["x", "y", "z"])));
+    }
+
+    #[test]
+    fn joint_consensus_needs_both_majorities() {
+        let quorum = joint(&["a", "b", "c"], &["c", "d", "e"]);
+        assert!(!quorum.is_met_by(votes(&["a", "b"])));
+        assert!(!quorum.is_met_by(votes(&["d", "e"])));
+        assert!(quorum.is_met_by(votes(&["a", "c", "d"])));
+        assert!(quorum.is_met_by(votes(&["a", "c", "e"])));
+        assert!(!quorum.is_met_by(votes(&["a", "b", "d"])));
+    }
+
+    #[test]
+    fn empty_configurations_are_skipped() {
+        assert!(Quorum::default().is_met_by(votes(&[])));
+
+        // bootstrapping goes from no voters to one
+        let quorum = joint(&[], &["a"]);
+        assert!(quorum.is_met_by(votes(&["a"])));
+        assert!(!quorum.is_met_by(votes(&[])));
+    }
+
+    #[test]
+    fn a_server_in_only_one_configuration_counts_only_there() {
+        // a leader removing itself still needs a majority of the others
+        let quorum = joint(&["a", "b", "c"], &["b", "c"]);
+        assert!(!quorum.is_met_by(votes(&["a", "b"])));
+        assert!(quorum.is_met_by(votes(&["b", "c"])));
+
+        // a leader adding a second server can't commit alone
+        let quorum = joint(&["a"], &["a", "b"]);
+        assert!(!quorum.is_met_by(votes(&["a"])));
+        assert!(quorum.is_met_by(votes(&["a", "b"])));
+    }
+
+    #[test]
+    fn servers_require_both_majorities_while_changing() {
+        let mut servers = Servers::default();
+        servers.visit(&servers.member_add("a").unwrap());
+        servers.visit(&ServerConfigChange {
+            current: set(&["a"]),
+            new: None,
+            learners: HashSet::new(),
+        });
+        assert!(!servers.is_changing());
+
+        let add = servers.member_add("b").unwrap();
+        servers.visit(&add);
+        assert!(servers.is_changing());
+        assert!(servers.member_add("c").is_none());
+        assert!(servers.member_remove("a").is_none());
+        assert!(!servers.quorum().is_met_by(votes(&["a"])));
+        assert!(servers.quorum().is_met_by(votes(&["a", "b"])));
     }
 }
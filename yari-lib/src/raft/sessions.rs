@@ -0,0 +1,219 @@
+//! Client sessions, which apply each command at most once.
+//!
+//! A client that retries a command against another server can't tell
+//! whether the first attempt was committed. So each command carries the
+//! client's id and a sequence number, and every server keeps a table of
+//! the latest sequence number applied for each client, with its result.
+//! A retry of that command gets the recorded result back instead of
+//! being applied again.
+//!
+//! The table is built by applying the log, so it is the same on every
+//! server. Sessions idle for too long are forgotten at an entry the
+//! leader appends, rather than by each server on its own clock; a
+//! command retried after its session expired is applied again.
+
+use super::{ProposalResult, RaftMessage, RaftState, StateMachine, TermIndex};
+use crate::{
+    storage::{LogStore, StableStore},
+    Index, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Identifies one command from a client. Sequence numbers must increase
+/// with each new command, and are reused only to retry one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientSession {
+    pub client_id: String,
+    pub sequence: u64,
+}
+
+impl ClientSession {
+    /// A session with a random client id, at its first command.
+    pub fn new() -> Self {
+        Self {
+            client_id: format!("{:016x}", fastrand::u64(..)),
+            sequence: 1,
+        }
+    }
+
+    /// Moves on to the client's next command.
+    pub fn next(&mut self) {
+        self.sequence += 1;
+    }
+}
+
+impl Default for ClientSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A state machine message sent within a client session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionMessage<MT> {
+    pub session: ClientSession,
+    pub message: MT,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Session<AR> {
+    sequence: u64,
+    result: AR,
+    last_index: Index,
+}
+
+/// The latest command applied for each client.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Sessions<AR>(HashMap<String, Session<AR>>);
+
+impl<AR> Default for Sessions<AR> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<AR> Sessions<AR>
+where
+    AR: Serialize + serde::de::DeserializeOwned + Clone,
+{
+    /// Applies the command at `index` with `apply`, unless `session` has
+    /// already been applied, in which case its recorded result is
+    /// returned. A command older than the client's latest has no result
+    /// to return any more, and is refused.
+    pub(crate) fn apply(
+        &mut self,
+        session: &ClientSession,
+        index: Index,
+        apply: impl FnOnce() -> AR,
+    ) -> ProposalResult<AR> {
+        match self.0.get_mut(&session.client_id) {
+            Some(existing) if existing.sequence == session.sequence => {
+                existing.last_index = index;
+                Ok(existing.result.clone())
+            }
+
+            Some(existing) if existing.sequence > session.sequence => Err(format!(
+                "client {} sent command {} after {}, so it was not applied",
+                session.client_id, session.sequence, existing.sequence
+            )),
+
+            _ => {
+                let result = apply();
+                self.0.insert(
+                    session.client_id.clone(),
+                    Session {
+                        sequence: session.sequence,
+                        result: result.clone(),
+                        last_index: index,
+                    },
+                );
+                Ok(result)
+            }
+        }
+    }
+
+    /// Forgets the sessions of `client_ids` that are still idle at
+    /// `index`. They were found idle when the entry was appended, but a
+    /// command applied since then may have used one again.
+    pub(crate) fn expire(&mut self, client_ids: &[String], index: Index, max_idle: usize) {
+        for client_id in client_ids {
+            if self
+                .0
+                .get(client_id)
+                .is_some_and(|session| index.saturating_sub(session.last_index) > max_idle)
+            {
+                self.0.remove(client_id);
+            }
+        }
+    }
+
+    /// The clients with no command applied within `max_idle` entries of
+    /// `index`.
+    fn idle(&self, index: Index, max_idle: usize) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(_, session)| index.saturating_sub(session.last_index) > max_idle)
+            .map(|(client_id, _)| client_id.clone())
+            .collect()
+    }
+
+    /// Encodes the table for a snapshot's metadata.
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Decodes a table written by [`to_bytes`](Self::to_bytes). Snapshots
+    /// taken before sessions existed have no bytes, and no sessions.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.is_empty() {
+            Ok(Self::default())
+        } else {
+            Ok(bincode::deserialize(bytes)?)
+        }
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    /// Appends an entry expiring the idle sessions, unless one is already
+    /// waiting to be applied.
+    pub(crate) async fn expire_sessions(&mut self) -> Result<()> {
+        if !self.is_leader()
+            || self
+                .session_expiry_index
+                .is_some_and(|index| index > self.last_applied_index)
+        {
+            return Ok(());
+        }
+
+        let idle = self.sessions.idle(
+            self.last_applied_index,
+            self.config.session_max_idle_entries(),
+        );
+        if !idle.is_empty() {
+            log::debug!("{}: expiring {} idle sessions", self.id, idle.len());
+            let TermIndex(_, index) = self
+                .client_append(RaftMessage::ExpireSessions(idle))
+                .await?;
+            self.session_expiry_index = Some(index);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(client_id: &str, sequence: u64) -> ClientSession {
+        ClientSession {
+            client_id: client_id.into(),
+            sequence,
+        }
+    }
+
+    #[test]
+    fn keeps_sessions_used_again_before_their_expiry_is_applied() {
+        let mut sessions = Sessions::default();
+        sessions.apply(&session("a", 1), 1, || 1).unwrap();
+        sessions.apply(&session("b", 1), 2, || 1).unwrap();
+
+        let idle = sessions.idle(13, 10);
+        assert_eq!(idle.len(), 2);
+
+        // a command from "a" is applied between the expiry's append and
+        // its application
+        sessions.apply(&session("a", 2), 14, || 2).unwrap();
+        sessions.expire(&idle, 15, 10);
+
+        let retried = sessions.apply(&session("a", 2), 16, || panic!("applied twice"));
+        assert_eq!(retried, Ok(2));
+        assert_eq!(sessions.apply(&session("b", 1), 17, || 3), Ok(3));
+    }
+}
@@ -0,0 +1,164 @@
+//! Leadership transfer.
+//!
+//! The leader stops taking proposals, waits for the target to catch up,
+//! then tells it to start an election at once with a TimeoutNow request.
+//! The transfer completes when the leader sees the target's higher term,
+//! and is abandoned after an election timeout.
+
+use super::{RaftMessage, RaftState, StateMachine};
+use crate::{
+    rpc::{TimeoutNowRequest, TimeoutNowResponse},
+    storage::{LogStore, StableStore},
+    Result,
+};
+use async_channel::{Receiver, Sender};
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Instant,
+};
+
+/// A leadership transfer in progress.
+#[derive(Debug)]
+pub(crate) struct LeadershipTransfer {
+    target: String,
+    deadline: Instant,
+    timeout_now_sent: bool,
+    done: Sender<bool>,
+}
+
+/// Why a leadership transfer could not start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferRefused {
+    /// The target is not a server this leader replicates to.
+    NotAFollower,
+    /// The target is a learner, which can't be elected.
+    NotAVoter,
+}
+
+impl Display for TransferRefused {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotAFollower => "the target is not a member of the cluster",
+            Self::NotAVoter => "the target is a learner, and can't be elected",
+        })
+    }
+}
+
+impl<SM, L, S> RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>
+where
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+{
+    /// Starts handing leadership to `target`, returning a receiver that
+    /// reports whether it succeeded.
+    pub fn transfer_leadership(
+        &mut self,
+        target: &str,
+    ) -> std::result::Result<Receiver<bool>, TransferRefused> {
+        let is_follower = self
+            .follower_state
+            .as_ref()
+            .is_some_and(|followers| followers.get(target).is_some());
+        if !is_follower {
+            return Err(TransferRefused::NotAFollower);
+        }
+
+        // a learner never stands for election, so the transfer could only
+        // hold up writes until it timed out
+        if !self.servers.is_voter(target) {
+            return Err(TransferRefused::NotAVoter);
+        }
+
+        self.end_transfer(false);
+        log::info!("{}: transferring leadership to {target}", self.id);
+
+        let (done, receiver) = async_channel::bounded(1);
+        self.transfer = Some(LeadershipTransfer {
+            target: target.to_string(),
+            deadline: Instant::now() + self.config.max_election_timeout(),
+            timeout_now_sent: false,
+            done,
+        });
+        self.send_timeout_now_if_caught_up();
+        Ok(receiver)
+    }
+
+    /// Whether new proposals should be refused because leadership is
+    /// being handed to another server.
+    pub fn is_transferring_leadership(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    /// Handles a TimeoutNow request from the leader by starting an
+    /// election as soon as the election thread wakes.
+    pub async fn timeout_now(&mut self, request: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+        if request.term == self.current_term && !self.is_leader() {
+            log::info!("{}: {} asked for an election", self.id, request.leader_id);
+            self.election_requested = true;
+            self.interrupt().await;
+        }
+
+        Ok(TimeoutNowResponse {
+            term: self.current_term,
+        })
+    }
+
+    pub(crate) fn take_election_request(&mut self) -> bool {
+        std::mem::take(&mut self.election_requested)
+    }
+
+    /// Sends TimeoutNow to the transfer target once it has every entry.
+    pub(crate) fn send_timeout_now_if_caught_up(&mut self) {
+        let last_index = self.log.last_index().unwrap_or(0);
+        let Some(transfer) = self.transfer.as_mut().filter(|t| !t.timeout_now_sent) else {
+            return;
+        };
+
+        let caught_up = self
+            .follower_state
+            .as_ref()
+            .and_then(|followers| followers.get(&transfer.target))
+            .is_some_and(|follower| follower.match_index >= last_index);
+
+        if caught_up {
+            transfer.timeout_now_sent = true;
+            self.timeout_now_term = Some(self.current_term);
+            let client = self.client.clone();
+            let target = transfer.target.clone();
+            let request = TimeoutNowRequest {
+                term: self.current_term,
+                leader_id: self.id.clone(),
+            };
+
+            async_global_executor::spawn(async move {
+                if let Err(e) = client.timeout_now(&target, &request).await {
+                    log::error!("could not send timeout_now to {target}: {e}");
+                }
+            })
+            .detach();
+        }
+    }
+
+    /// Abandons a transfer that has run past its deadline.
+    pub(crate) fn check_transfer_deadline(&mut self) {
+        if self
+            .transfer
+            .as_ref()
+            .is_some_and(|transfer| transfer.deadline <= Instant::now())
+        {
+            log::info!("{}: leadership transfer timed out", self.id);
+            self.end_transfer(false);
+        }
+    }
+
+    /// Ends any transfer in progress. It only counts as a success once
+    /// the target has been told to start its election.
+    pub(crate) fn end_transfer(&mut self, succeeded: bool) {
+        if let Some(transfer) = self.transfer.take() {
+            let _ = transfer
+                .done
+                .try_send(succeeded && transfer.timeout_now_sent);
+        }
+    }
+}
@@ -1,7 +1,11 @@
-use crate::{Index, LogEntry, Message, Result, StateMachine, Term};
+use crate::{
+    raft::{ClientSession, RaftMessage, ServerConfigChange, SessionMessage},
+    Error, Index, LogEntry, Message, Result, StateMachine, Term,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::marker::PhantomData;
-use trillium_client::Client;
+use std::{collections::BTreeSet, marker::PhantomData, str::FromStr};
+use trillium_client::{Client, Conn, UnexpectedStatusError};
+use trillium_http::{KnownHeaderName, Method, Status};
 use trillium_smol::ClientConfig;
 use url::Url;
 
@@ -10,9 +14,88 @@ pub struct ClientResponse<R> {
     pub result: R,
 }
 
+/// A message to apply through the log. Every request to `/client` is
+/// linearizable, so unlike [`QueryRequest`] it takes no
+/// [`ReadConsistency`].
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientRequest<M> {
     pub message: M,
+    /// Makes retries of this request safe, by applying it at most once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<ClientSession>,
+}
+
+impl<M> From<ClientRequest<M>> for RaftMessage<M> {
+    fn from(ClientRequest { message, session }: ClientRequest<M>) -> Self {
+        match session {
+            Some(session) => Self::SessionMessage(SessionMessage { session, message }),
+            None => Self::StateMachineMessage(message),
+        }
+    }
+}
+
+/// A read-only query for `/query`.
+///
+/// `read_consistency` is taken here rather than on `/client`: answering
+/// from local state is only safe for a query, and a message sent to
+/// `/client` may change the state machine.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueryRequest<Q> {
+    pub query: Q,
+    #[serde(default)]
+    pub read_consistency: ReadConsistency,
+}
+
+/// The answer to a query, and the index of the last entry applied to the
+/// state it was answered from.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct QueryResponse<R> {
+    pub result: R,
+    pub applied_index: Index,
+}
+
+/// A follower asking the leader which index a read must wait for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadIndexRequest {
+    #[serde(default)]
+    pub read_consistency: ReadConsistency,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadIndexResponse {
+    pub index: Index,
+}
+
+/// How up to date the state a query is answered from must be.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadConsistency {
+    /// Reflects every write committed before the query, confirmed with a
+    /// round of heartbeats.
+    #[default]
+    Linearizable,
+    /// Like `Linearizable`, but skips the heartbeats while the leader
+    /// holds a lease, which is only safe if clocks drift less than the
+    /// configured bound.
+    Lease,
+    /// Answered from whatever state the receiving server has applied,
+    /// without contacting the leader.
+    Stale,
+}
+
+impl FromStr for ReadConsistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "linearizable" => Ok(Self::Linearizable),
+            "lease" => Ok(Self::Lease),
+            "stale" => Ok(Self::Stale),
+            other => Err(format!(
+                "{other} is not a read consistency, try linearizable, lease, or stale"
+            )),
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VoteResponse {
@@ -20,18 +103,34 @@ pub struct VoteResponse {
     pub vote_granted: bool,
 }
 
+/// A pre-vote asks whether the server would grant a vote in `term`,
+/// without either server changing its term.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct VoteRequest {
     pub term: Term,
     pub candidate_id: String,
     pub last_log_index: Option<Index>,
     pub last_log_term: Option<Term>,
+    #[serde(default)]
+    pub pre_vote: bool,
+    /// Set when the leader asked for this election, which followers grant
+    /// even while they are still hearing from that leader.
+    #[serde(default)]
+    pub leadership_transfer: bool,
 }
 
+/// When `success` is false, `conflict_term` and `conflict_index` tell
+/// the leader where the follower's log diverges. See [`Conflict`].
+///
+/// [`Conflict`]: crate::log::Conflict
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AppendResponse {
     pub term: Term,
     pub success: bool,
+    #[serde(default)]
+    pub conflict_term: Option<Term>,
+    #[serde(default)]
+    pub conflict_index: Option<Index>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -44,6 +143,95 @@ pub struct AppendRequest<M> {
     pub leader_commit_index: Index,
 }
 
+/// One chunk of a snapshot, sent in order starting at offset zero. The
+/// follower installs the snapshot once it receives the chunk marked
+/// `done`.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct InstallSnapshotRequest {
+    pub term: Term,
+    pub leader_id: String,
+    pub last_included_index: Index,
+    pub last_included_term: Term,
+    pub config: Option<ServerConfigChange>,
+    #[serde(default, with = "crate::base64")]
+    pub sessions: Vec<u8>,
+    pub offset: u64,
+    #[serde(with = "crate::base64")]
+    pub data: Vec<u8>,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotResponse {
+    pub term: Term,
+}
+
+/// Sent by a leader handing over leadership, asking the target to start
+/// an election without waiting for its election timeout.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TimeoutNowRequest {
+    pub term: Term,
+    pub leader_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TimeoutNowResponse {
+    pub term: Term,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferLeaderRequest {
+    pub target: String,
+}
+
+/// A server configuration, as reported by `GET /servers`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Membership {
+    /// The index of the log entry holding this configuration, unless it
+    /// was restored from a snapshot.
+    pub index: Option<Index>,
+    pub voters: BTreeSet<String>,
+    /// During joint consensus, the voters being changed to.
+    pub new_voters: Option<BTreeSet<String>>,
+    pub learners: BTreeSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberRole {
+    Voter,
+    /// A voter in the new configuration of a pending change.
+    Joining,
+    /// A voter in the old configuration of a pending change.
+    Leaving,
+    Learner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub id: String,
+    pub role: MemberRole,
+    pub leader: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembersResponse {
+    pub committed: Option<Membership>,
+    /// A configuration that has been appended to the log but is not yet
+    /// committed.
+    pub pending: Option<Membership>,
+    /// The servers in the latest configuration.
+    pub members: Vec<Member>,
+}
+
+/// The body of an error response, explaining why a request failed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+const MAX_REDIRECTS: usize = 5;
+
 #[derive(Debug)]
 pub struct RaftClient<S>(Client, PhantomData<S>);
 
@@ -74,6 +262,69 @@ impl<S: StateMachine> RaftClient<S> {
             .map_err(Into::into)
     }
 
+    /// Like `Conn::success`, but uses the explanation in an
+    /// [`ErrorResponse`] body when the server sent one.
+    async fn success_or_explain(conn: Conn) -> Result<Conn> {
+        let error = match conn.success() {
+            Ok(conn) => return Ok(conn),
+            Err(error) => error,
+        };
+
+        let mut conn = Conn::from(error);
+        let status = conn.status().unwrap_or(Status::InternalServerError);
+        match conn.response_json::<ErrorResponse>().await {
+            Ok(ErrorResponse { error }) => Err(Error::ErrorResponse(status, error)),
+            Err(_) => Err(UnexpectedStatusError::from(conn).into()),
+        }
+    }
+
+    /// Sends a request, following redirects unless `follow` is false, in
+    /// which case a redirect is returned as [`Error::Redirected`]. Returns
+    /// the url that answered along with its successful response.
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        mut url: Url,
+        body: Option<&impl serde::Serialize>,
+        follow: bool,
+    ) -> Result<(Url, Conn)> {
+        for _ in 0..MAX_REDIRECTS {
+            let mut conn = self.0.build_conn(method, url.clone());
+            if let Some(body) = body {
+                conn = conn.with_json_body(body)?;
+            }
+
+            let conn = conn.await?;
+            let location = conn
+                .status()
+                .filter(Status::is_redirection)
+                .and_then(|_| conn.response_headers().get_str(KnownHeaderName::Location));
+
+            match location {
+                Some(location) => {
+                    let location = url
+                        .join(location)
+                        .map_err(|e| format!("invalid redirect to {location}: {e}"))?;
+                    if !follow {
+                        return Err(Error::Redirected(location));
+                    }
+                    url = location;
+                }
+
+                None => return Ok((url, Self::success_or_explain(conn).await?)),
+            }
+        }
+
+        Err(Error::Str("too many redirects"))
+    }
+
+    /// Lists the cluster's members, following redirects to the leader.
+    pub async fn members(&self, url: &Url) -> Result<MembersResponse> {
+        let url = url.join("/servers").unwrap();
+        let (_, mut conn) = self.send(Method::Get, url, None::<&()>, true).await?;
+        conn.response_json().await.map_err(Into::into)
+    }
+
     pub async fn remove(&self, url: &Url, id: &str) -> Result<()> {
         let req_url = url
             .join(&format!("/servers/{}", urlencoding::encode(id)))
@@ -95,16 +346,65 @@ impl<S: StateMachine> RaftClient<S> {
         let url = url
             .join(&format!("/servers/{}", urlencoding::encode(id)))
             .unwrap();
+        let _ = Self::success_or_explain(self.0.put(url).await?).await?;
+        Ok(())
+    }
+
+    pub async fn add_learner(&self, url: &Url, id: &str) -> Result<()> {
+        let url = url
+            .join(&format!("/learners/{}", urlencoding::encode(id)))
+            .unwrap();
         let _ = self.0.put(url).await?.success()?;
         Ok(())
     }
 
+    pub async fn promote(&self, url: &Url, id: &str) -> Result<()> {
+        let url = url
+            .join(&format!("/learners/{}/promote", urlencoding::encode(id)))
+            .unwrap();
+        let _ = self.0.post(url).await?.success()?;
+        Ok(())
+    }
+
+    pub async fn transfer_leader(&self, url: &Url, target: &str) -> Result<()> {
+        let url = url.join("/leader/transfer").unwrap();
+        let request = TransferLeaderRequest {
+            target: target.to_string(),
+        };
+        let conn = self.0.post(url).with_json_body(&request)?.await?;
+        let _ = Self::success_or_explain(conn).await?;
+        Ok(())
+    }
+
     pub async fn client_append(
         &self,
         server: &Url,
         message: &ClientRequest<S::MessageType>,
     ) -> Result<ClientResponse<S::ApplyResult>> {
-        self.post(server.join("/client").unwrap(), message).await
+        let url = server.join("/client").unwrap();
+        let conn = self.0.post(url).with_json_body(message)?.await?;
+        Self::success_or_explain(conn)
+            .await?
+            .response_json()
+            .await
+            .map_err(Into::into)
+    }
+
+    pub async fn query(
+        &self,
+        server: &Url,
+        query: &QueryRequest<S::QueryType>,
+    ) -> Result<QueryResponse<S::ApplyResult>> {
+        self.post(server.join("/query").unwrap(), query).await
+    }
+
+    pub async fn read_index(
+        &self,
+        server: &str,
+        request: &ReadIndexRequest,
+    ) -> Result<ReadIndexResponse> {
+        let url = Url::parse(server).unwrap().join("/read_index").unwrap();
+        self.post(url, request).await
     }
 
     pub async fn request_vote(
@@ -116,6 +416,15 @@ impl<S: StateMachine> RaftClient<S> {
         self.post(url, vote_request).await
     }
 
+    pub async fn request_pre_vote(
+        &self,
+        server: &str,
+        vote_request: &VoteRequest,
+    ) -> Result<VoteResponse> {
+        let url = Url::parse(server).unwrap().join("/prevote").unwrap();
+        self.post(url, vote_request).await
+    }
+
     pub async fn append(
         &self,
         server: &str,
@@ -125,6 +434,27 @@ impl<S: StateMachine> RaftClient<S> {
         self.post(url, append_request).await
     }
 
+    pub async fn install_snapshot(
+        &self,
+        server: &str,
+        install_snapshot_request: &InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let url = Url::parse(server)
+            .unwrap()
+            .join("/install_snapshot")
+            .unwrap();
+        self.post(url, install_snapshot_request).await
+    }
+
+    pub async fn timeout_now(
+        &self,
+        server: &str,
+        timeout_now_request: &TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse> {
+        let url = Url::parse(server).unwrap().join("/timeout_now").unwrap();
+        self.post(url, timeout_now_request).await
+    }
+
     pub fn new() -> Self {
         Self(Client::new(ClientConfig::new()), PhantomData)
     }
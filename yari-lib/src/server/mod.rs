@@ -1,87 +1,294 @@
 use crate::{
-    raft::{ElectionThread, RaftMessage, StateMachine},
-    rpc::{AppendRequest, AppendResponse, ClientRequest, VoteRequest, VoteResponse},
+    eventstream::EventStream,
+    raft::{self, ElectionThread, RaftMessage, StateMachine, TransferRefused},
+    rpc::{
+        AppendRequest, AppendResponse, ClientRequest, ErrorResponse, InstallSnapshotRequest,
+        InstallSnapshotResponse, MembersResponse, QueryRequest, QueryResponse, ReadIndexRequest,
+        ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, TransferLeaderRequest,
+        VoteRequest, VoteResponse,
+    },
+    storage::{LogStore, StableStore},
     RaftState,
 };
+use async_io::Timer;
 use async_lock::RwLock;
+use futures_lite::FutureExt;
 use serde_json::{json, Value};
 use std::{net::SocketAddr, sync::Arc};
-use trillium::{Conn, Status};
+use trillium::{Conn, HttpConfig, KnownHeaderName, Status};
 use trillium_api::{api, FromConn, Json, State};
 use trillium_http::Stopper;
-use trillium_redirect::Redirect;
+use trillium_redirect::{Redirect, RedirectStatus};
 use trillium_router::RouterConnExt;
 use trillium_server_common::ServerHandle;
 use url::Url;
 
 trait RaftStateExt {
-    fn raft_state<SM: StateMachine>(&self) -> WebState<SM>;
+    fn raft_state<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+        &self,
+    ) -> WebState<SM, L, S>;
 }
 
 impl RaftStateExt for Conn {
-    fn raft_state<SM: StateMachine>(&self) -> WebState<SM> {
-        self.state::<WebState<SM>>().unwrap().clone()
+    fn raft_state<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+        &self,
+    ) -> WebState<SM, L, S> {
+        self.state::<WebState<SM, L, S>>().unwrap().clone()
     }
 }
 
-async fn append<SM: StateMachine>(
+async fn append<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
     conn: &mut Conn,
     Json(append_request): Json<AppendRequest<RaftMessage<SM::MessageType>>>,
-) -> Json<AppendResponse> {
-    let state = conn.raft_state::<SM>();
+) -> Result<Json<AppendResponse>, Status> {
+    let state = conn.raft_state::<SM, L, S>();
     let mut state = state.write().await;
-    Json(state.append(append_request).await)
+    state
+        .append(append_request)
+        .await
+        .map(Json)
+        .map_err(internal_error)
 }
 
-async fn vote<SM: StateMachine>(
+async fn vote<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
     conn: &mut Conn,
     Json(vote_request): Json<VoteRequest>,
-) -> Json<VoteResponse> {
-    let state = conn.raft_state::<SM>();
+) -> Result<Json<VoteResponse>, Status> {
+    let state = conn.raft_state::<SM, L, S>();
     let mut state = state.write().await;
-    Json(state.vote(vote_request).await)
+    state
+        .vote(vote_request)
+        .await
+        .map(Json)
+        .map_err(internal_error)
 }
 
-async fn client<SM: StateMachine>(
-    _: &mut Conn,
-    (Json(client_request), WebRaftState(raft)): (
-        Json<ClientRequest<SM::MessageType>>,
-        WebRaftState<SM>,
-    ),
-) -> Result<Json<Value>, Result<Redirect, Status>> {
-    if raft.read().await.is_leader() {
-        let mut receiver = {
-            let mut raft = raft.write().await;
-            let term_index =
-                raft.client_append(RaftMessage::StateMachineMessage(client_request.message));
-            raft.receive_applied_result(term_index)
-        };
-        let apply_result = receiver.recv().await.unwrap();
-        Ok(Json(json!({"result": apply_result})))
-    } else if let Some(leader) = raft.read().await.leader_id_for_client_redirection() {
-        Err(Ok(Redirect::to(leader.to_string())))
-    } else {
-        Err(Err(Status::ServiceUnavailable))
+async fn pre_vote<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    conn: &mut Conn,
+    Json(vote_request): Json<VoteRequest>,
+) -> Result<Json<VoteResponse>, Status> {
+    let state = conn.raft_state::<SM, L, S>();
+    let state = state.read().await;
+    Ok(Json(state.pre_vote(&vote_request)))
+}
+
+async fn install_snapshot<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
+    conn: &mut Conn,
+    Json(install_snapshot_request): Json<InstallSnapshotRequest>,
+) -> Result<Json<InstallSnapshotResponse>, Status> {
+    let state = conn.raft_state::<SM, L, S>();
+    let mut state = state.write().await;
+    state
+        .install_snapshot(install_snapshot_request)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn timeout_now<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
+    conn: &mut Conn,
+    Json(timeout_now_request): Json<TimeoutNowRequest>,
+) -> Result<Json<TimeoutNowResponse>, Status> {
+    let state = conn.raft_state::<SM, L, S>();
+    let mut state = state.write().await;
+    state
+        .timeout_now(timeout_now_request)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn transfer_leader<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
+    conn: &mut Conn,
+    Json(request): Json<TransferLeaderRequest>,
+) -> Result<Status, Result<Redirect, (Status, Json<ErrorResponse>)>> {
+    let raft = conn.raft_state::<SM, L, S>();
+    let receiver = {
+        let mut raft = raft.write().await;
+        if !raft.is_leader() {
+            return Err(leader_redirect("/leader/transfer", &*raft)
+                .map_err(|status| error_response(status, "no leader is known")));
+        }
+
+        raft.transfer_leadership(&request.target)
+            .map_err(|refused| {
+                let status = match refused {
+                    TransferRefused::NotAFollower => Status::NotFound,
+                    TransferRefused::NotAVoter => Status::Conflict,
+                };
+                Err(error_response(status, refused.to_string()))
+            })?
+    };
+
+    match receiver.recv().await {
+        Ok(true) => Ok(Status::Ok),
+        _ => Err(Err(error_response(
+            Status::ServiceUnavailable,
+            format!("{} did not take over in time", request.target),
+        ))),
+    }
+}
+
+fn error_response(status: Status, error: impl Into<String>) -> (Status, Json<ErrorResponse>) {
+    let error = error.into();
+    (status, Json(ErrorResponse { error }))
+}
+
+fn internal_error(error: crate::Error) -> Status {
+    log::error!("{error}");
+    Status::InternalServerError
+}
+
+async fn client<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    conn: &mut Conn,
+    Json(client_request): Json<ClientRequest<SM::MessageType>>,
+) -> Result<Json<Value>, Result<Redirect, (Status, Json<ErrorResponse>)>> {
+    let raft = conn.raft_state::<SM, L, S>();
+    let timeout = {
+        let raft = raft.read().await;
+        if raft.is_transferring_leadership() {
+            return Err(Err(error_response(
+                Status::ServiceUnavailable,
+                "leadership is being transferred, retry",
+            )));
+        } else if !raft.is_leader() {
+            return Err(leader_redirect("/client", &*raft)
+                .map_err(|status| error_response(status, "no leader is known, retry")));
+        }
+        raft.config().proposal_timeout()
+    };
+
+    let applied = async {
+        let mut receiver = raft::propose(&raft, client_request.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        receiver
+            .recv()
+            .await
+            .unwrap_or_else(|_| Err("the entry was dropped before it was applied, retry".into()))
+    };
+    let timed_out = async {
+        Timer::after(timeout).await;
+        Err(format!(
+            "the entry was not applied within {}ms; it may still be, so retry with the same session",
+            timeout.as_millis()
+        ))
+    };
+
+    match applied.or(timed_out).await {
+        Ok(apply_result) => Ok(Json(json!({"result": apply_result}))),
+        Err(reason) => Err(Err(error_response(Status::ServiceUnavailable, reason))),
+    }
+}
+
+async fn query<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    conn: &mut Conn,
+    Json(query_request): Json<QueryRequest<SM::QueryType>>,
+) -> Result<Json<QueryResponse<SM::ApplyResult>>, (Status, Json<ErrorResponse>)> {
+    let raft = conn.raft_state::<SM, L, S>();
+    let consistency = query_request.read_consistency;
+    match raft::query(&raft, &query_request.query, consistency).await {
+        Ok((result, applied_index)) => Ok(Json(QueryResponse {
+            result,
+            applied_index,
+        })),
+        Err(e) => Err(error_response(Status::ServiceUnavailable, e.to_string())),
+    }
+}
+
+async fn read_index<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    conn: &mut Conn,
+    Json(request): Json<ReadIndexRequest>,
+) -> Result<Json<ReadIndexResponse>, (Status, Json<ErrorResponse>)> {
+    let raft = conn.raft_state::<SM, L, S>();
+    match raft::read_index(&raft, request.read_consistency).await {
+        Ok(index) => Ok(Json(ReadIndexResponse { index })),
+        Err(e) => Err(error_response(Status::ServiceUnavailable, e.to_string())),
     }
 }
 
-fn leader_redirect<SM: StateMachine>(
-    id: &str,
-    raft: &RaftState<SM, SM::MessageType, SM::ApplyResult>,
+/// Streams the messages applied from `from_index` on, or from now if
+/// it's absent, as server-sent events with the log index as their id. A
+/// `filter` is passed to the state machine to choose which messages are
+/// sent.
+async fn watch<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    conn: &mut Conn,
+    WebRaftState(raft): WebRaftState<SM, L, S>,
+) -> Result<Status, (Status, Json<ErrorResponse>)> {
+    let mut from_index = None;
+    let mut filter = None;
+    for (key, value) in url::form_urlencoded::parse(conn.querystring().as_bytes()) {
+        match &*key {
+            "from_index" => {
+                from_index = Some(value.parse().map_err(|_| {
+                    error_response(Status::BadRequest, format!("{value} is not a log index"))
+                })?)
+            }
+            "filter" => filter = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let watcher = raft
+        .read()
+        .await
+        .watch(from_index, filter)
+        .map_err(|oldest| {
+            error_response(
+                Status::Gone,
+                format!("messages before {oldest} are no longer available"),
+            )
+        })?;
+
+    conn.response_headers_mut()
+        .insert(KnownHeaderName::ContentType, "text/event-stream");
+    conn.response_headers_mut()
+        .insert(KnownHeaderName::CacheControl, "no-cache");
+    // the stream never ends on its own, and would hold up shutdown
+    let watcher = conn.inner().stopper().stop_stream(watcher);
+    conn.set_body(watcher.into_body());
+    Ok(Status::Ok)
+}
+
+/// Redirects to `path` on the leader, keeping the request's method and
+/// body.
+fn leader_redirect<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    path: &str,
+    raft: &RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>,
 ) -> Result<Redirect, Status> {
     match raft.leader_id_for_client_redirection() {
-        Some(redirect) => {
-            let location = Url::parse(id)
-                .and_then(|u| u.join("/servers/"))
-                .and_then(|u| u.join(&urlencoding::encode(redirect)))
+        Some(leader) => {
+            let location = Url::parse(leader)
+                .and_then(|u| u.join(path))
                 .map_err(|_| Status::InternalServerError)?;
-            Ok(Redirect::to(location.to_string()))
+            Ok(Redirect::to(location.to_string())
+                .with_redirect_status(RedirectStatus::TemporaryRedirect))
         }
 
-        None => Err(Status::InternalServerError),
+        None => Err(Status::ServiceUnavailable),
     }
 }
 
+fn servers_path(id: &str) -> String {
+    format!("/servers/{}", urlencoding::encode(id))
+}
+
+fn learners_path(id: &str) -> String {
+    format!("/learners/{}", urlencoding::encode(id))
+}
+
 struct Id(String);
 #[trillium::async_trait]
 impl FromConn for Id {
@@ -95,56 +302,158 @@ impl FromConn for Id {
     }
 }
 
-async fn add_server<SM: StateMachine>(
+async fn add_server<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    _: &mut Conn,
+    (Id(id), State(raft)): (Id, State<WebState<SM, L, S>>),
+) -> Result<Status, Result<Redirect, (Status, Json<ErrorResponse>)>> {
+    let caught_up = {
+        let mut raft = raft.write().await;
+
+        if raft.is_transferring_leadership() {
+            return Err(Err(error_response(
+                Status::ServiceUnavailable,
+                "leadership is being transferred",
+            )));
+        } else if !raft.is_leader() {
+            return Err(leader_redirect(&servers_path(&id), &*raft)
+                .map_err(|status| error_response(status, "no leader is known")));
+        } else if raft.is_changing_membership() {
+            return Err(Err(error_response(
+                Status::Conflict,
+                "another membership change is in progress",
+            )));
+        }
+
+        raft.member_add_after_catch_up(&id).await.map_err(|e| {
+            let message = e.to_string();
+            Err(error_response(internal_error(e), message))
+        })?
+    };
+
+    match caught_up.recv().await {
+        Ok(Ok(())) => Ok(Status::Ok),
+        Ok(Err(reason)) => Err(Err(error_response(Status::ServiceUnavailable, reason))),
+        Err(_) => Err(Err(error_response(
+            Status::ServiceUnavailable,
+            format!("gave up on adding {id}"),
+        ))),
+    }
+}
+
+async fn add_learner<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
     _: &mut Conn,
-    (Id(id), State(raft)): (Id, State<WebState<SM>>),
+    (Id(id), State(raft)): (Id, State<WebState<SM, L, S>>),
 ) -> Result<Status, Result<Redirect, Status>> {
     let mut raft = raft.write().await;
 
-    if raft.is_leader() {
-        raft.member_add(&id);
-        Ok(Status::Ok)
+    if raft.is_transferring_leadership() {
+        Err(Err(Status::ServiceUnavailable))
+    } else if raft.is_leader() && raft.is_changing_membership() {
+        Err(Err(Status::Conflict))
+    } else if raft.is_leader() {
+        match raft.learner_add(&id).await {
+            Ok(true) => Ok(Status::Ok),
+            Ok(false) => Err(Err(Status::Conflict)),
+            Err(e) => Err(Err(internal_error(e))),
+        }
     } else {
-        Err(leader_redirect(&id, &*raft))
+        Err(leader_redirect(&learners_path(&id), &*raft))
     }
 }
 
-struct WebRaftState<SM: StateMachine>(WebState<SM>);
+async fn promote_learner<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
+    _: &mut Conn,
+    (Id(id), State(raft)): (Id, State<WebState<SM, L, S>>),
+) -> Result<Status, Result<Redirect, Status>> {
+    let mut raft = raft.write().await;
+
+    if raft.is_transferring_leadership() {
+        Err(Err(Status::ServiceUnavailable))
+    } else if raft.is_leader() && raft.is_changing_membership() {
+        Err(Err(Status::Conflict))
+    } else if raft.is_leader() {
+        match raft.learner_promote(&id).await {
+            Ok(true) => Ok(Status::Ok),
+            Ok(false) => Err(Err(Status::NotFound)),
+            Err(e) => Err(Err(internal_error(e))),
+        }
+    } else {
+        let path = format!("{}/promote", learners_path(&id));
+        Err(leader_redirect(&path, &*raft))
+    }
+}
+
+struct WebRaftState<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    WebState<SM, L, S>,
+);
 #[trillium::async_trait]
-impl<SM: StateMachine> FromConn for WebRaftState<SM> {
+impl<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore> FromConn
+    for WebRaftState<SM, L, S>
+{
     async fn from_conn(conn: &mut Conn) -> Option<Self> {
         conn.take_state().map(Self)
     }
 }
 
-async fn remove_server<SM: StateMachine>(
+async fn remove_server<
+    SM: StateMachine,
+    L: LogStore<RaftMessage<SM::MessageType>>,
+    S: StableStore,
+>(
     _: &mut Conn,
-    (Id(id), WebRaftState(raft)): (Id, WebRaftState<SM>),
+    (Id(id), WebRaftState(raft)): (Id, WebRaftState<SM, L, S>),
 ) -> Result<Status, Result<Redirect, Status>> {
     let mut raft = raft.write().await;
 
+    if raft.is_transferring_leadership() {
+        Err(Err(Status::ServiceUnavailable))
+    } else if raft.is_leader() {
+        match raft.member_remove(&id).await {
+            Ok(true) => Ok(Status::Ok),
+            Ok(false) => Err(Err(Status::Conflict)),
+            Err(e) => Err(Err(internal_error(e))),
+        }
+    } else {
+        Err(leader_redirect(&servers_path(&id), &*raft))
+    }
+}
+
+async fn members<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    _: &mut Conn,
+    WebRaftState(raft): WebRaftState<SM, L, S>,
+) -> Result<Json<MembersResponse>, Result<Redirect, Status>> {
+    let raft = raft.read().await;
     if raft.is_leader() {
-        raft.member_remove(&id);
-        Ok(Status::Ok)
+        Ok(Json(raft.members()))
     } else {
-        Err(leader_redirect(&id, &*raft))
+        Err(leader_redirect("/servers", &*raft))
     }
 }
 
-async fn status<SM: StateMachine>(
+async fn status<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
     _: &mut Conn,
-    WebRaftState(raft): WebRaftState<SM>,
+    WebRaftState(raft): WebRaftState<SM, L, S>,
 ) -> Json<Value> {
     let state = raft.read().await;
     Json(serde_json::to_value(&*state).unwrap())
 }
 
-type WebState<SM> = Arc<
-    RwLock<RaftState<SM, <SM as StateMachine>::MessageType, <SM as StateMachine>::ApplyResult>>,
+type WebState<SM, L, S> = Arc<
+    RwLock<
+        RaftState<SM, <SM as StateMachine>::MessageType, <SM as StateMachine>::ApplyResult, L, S>,
+    >,
 >;
 
-pub async fn start<SM: StateMachine>(
-    state: RaftState<SM, SM::MessageType, SM::ApplyResult>,
+pub async fn start<SM: StateMachine, L: LogStore<RaftMessage<SM::MessageType>>, S: StableStore>(
+    state: RaftState<SM, SM::MessageType, SM::ApplyResult, L, S>,
     socket_addr: SocketAddr,
 ) -> ServerHandle {
     let stopper = Stopper::new();
@@ -163,16 +472,29 @@ pub async fn start<SM: StateMachine>(
 
     trillium_smol::config()
         .with_stopper(stopper)
+        // trillium holds the start of a response until its buffer fills or
+        // the body ends, which would keep watchers waiting on events
+        .with_http_config(HttpConfig::default().with_response_buffer_len(0))
         .with_socketaddr(socket_addr)
         .spawn((
             trillium::state(state),
             trillium_logger::logger(),
             trillium_router::router()
-                .get("/", api(status::<SM>))
-                .post("/append", api(append::<SM>))
-                .post("/vote", api(vote::<SM>))
-                .post("/client", api(client::<SM>))
-                .put("/servers/:id", api(add_server::<SM>))
-                .delete("/servers/:id", api(remove_server::<SM>)),
+                .get("/", api(status::<SM, L, S>))
+                .post("/append", api(append::<SM, L, S>))
+                .post("/vote", api(vote::<SM, L, S>))
+                .post("/prevote", api(pre_vote::<SM, L, S>))
+                .post("/install_snapshot", api(install_snapshot::<SM, L, S>))
+                .post("/timeout_now", api(timeout_now::<SM, L, S>))
+                .post("/leader/transfer", api(transfer_leader::<SM, L, S>))
+                .post("/client", api(client::<SM, L, S>))
+                .post("/query", api(query::<SM, L, S>))
+                .post("/read_index", api(read_index::<SM, L, S>))
+                .get("/watch", api(watch::<SM, L, S>))
+                .get("/servers", api(members::<SM, L, S>))
+                .put("/servers/:id", api(add_server::<SM, L, S>))
+                .delete("/servers/:id", api(remove_server::<SM, L, S>))
+                .put("/learners/:id", api(add_learner::<SM, L, S>))
+                .post("/learners/:id/promote", api(promote_learner::<SM, L, S>)),
         ))
 }
@@ -1,67 +1,244 @@
-use broadcaster::BroadcastChannel;
-use crate::RaftState;
+//! Streams applied state machine messages to watchers.
+//!
+//! Every message applied to the state machine is sent to the current
+//! watchers along with its result, and kept in a bounded history so a
+//! watcher can start from a recent index, or resume from the last index
+//! it saw after reconnecting.
+
+use crate::{eventstream::Event, Index};
+use async_broadcast::{InactiveReceiver, Receiver, RecvError, Sender};
+use futures_lite::{ready, Stream};
 use serde::Serialize;
-use crate::eventstream::{Event, EventStream};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Formatter},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-#[derive(Clone, Debug)]
-pub struct SSEChannel(BroadcastChannel<SSEvent>);
+/// How many events a watcher may fall behind by before it is
+/// disconnected.
+const WATCHER_CAPACITY: usize = 1024;
 
-impl Default for SSEChannel {
-    fn default() -> Self {
-        Self(BroadcastChannel::new())
-    }
+#[derive(Debug)]
+pub struct SSEChannel<MT> {
+    sender: Sender<SSEvent<MT>>,
+    _inactive: InactiveReceiver<SSEvent<MT>>,
+    history: VecDeque<SSEvent<MT>>,
+    history_len: usize,
+    /// Every event from this index on is still in the history.
+    retained_from: Index,
 }
 
-impl SSEChannel {
-    pub async fn send(&self, item: SSEvent) -> anyhow::Result<()> {
-        Ok(self.0.send(&item).await?)
+impl<MT: Clone> SSEChannel<MT> {
+    pub fn new(history_len: usize) -> Self {
+        let (sender, _inactive) = broadcast();
+        Self {
+            sender,
+            _inactive,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            retained_from: 1,
+        }
     }
 
-    pub async fn recv(&mut self) -> Option<SSEvent> {
-        self.0.recv().await
-    }
+    /// Sends the message applied at `index`, with its result, to every
+    /// watcher.
+    pub fn send<AR: Serialize>(&mut self, index: Index, message: &MT, result: &AR)
+    where
+        MT: Serialize,
+    {
+        let data = match serde_json::to_string(&AppliedEvent {
+            index,
+            message,
+            result,
+        }) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("could not encode the message applied at {index}: {e}");
+                return;
+            }
+        };
 
-    pub fn into_inner(self) -> BroadcastChannel<SSEvent> {
-        self.0
-    }
+        let event = SSEvent {
+            index,
+            id: index.to_string(),
+            message: message.clone(),
+            data,
+        };
 
-    pub fn into_response(self) -> tide::Response {
-        self.into_inner().into_response()
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                if let Some(evicted) = self.history.pop_front() {
+                    self.retained_from = evicted.index + 1;
+                }
+            }
+            self.history.push_back(event.clone());
+        } else {
+            self.retained_from = index + 1;
+        }
+
+        let _ = self.sender.try_broadcast(event);
     }
 
-    pub async fn state<X, Y: Serialize>(&self, raft: &RaftState<X, Y>) {
-        self.send(SSEvent::State(serde_json::to_string(raft).unwrap()))
-            .await
-            .expect("channel.state")
+    /// Drops the history up to and including `index`, whose events
+    /// can't be sent any more, such as when a snapshot replaces the
+    /// state machine.
+    ///
+    /// Open watchers may not have been sent every event up to `index`,
+    /// so each ends once it has been sent those already queued for it. A
+    /// watcher that reconnects from where it left off is then told what
+    /// it missed is gone, rather than silently skipping it.
+    pub fn forget_through(&mut self, index: Index) {
+        self.history.retain(|event| event.index > index);
+        self.retained_from = self.retained_from.max(index + 1);
+
+        let (sender, inactive) = broadcast();
+        self.sender.close();
+        self.sender = sender;
+        self._inactive = inactive;
     }
 
-    pub async fn log(&self, message: String) {
-        self.send(SSEvent::Log(message)).await.expect("channel.log")
+    /// The events from `from_index` on that `filter` accepts, starting
+    /// with those in the history. Returns the oldest index that can be
+    /// watched from if `from_index` is older than the history.
+    pub fn watch(
+        &self,
+        from_index: Index,
+        filter: impl Fn(&MT) -> bool + Send + Sync + 'static,
+    ) -> Result<Watcher<MT>, Index> {
+        if from_index < self.retained_from {
+            return Err(self.retained_from);
+        }
+
+        Ok(Watcher {
+            history: self
+                .history
+                .iter()
+                .filter(|event| event.index >= from_index)
+                .cloned()
+                .collect(),
+            receiver: Box::pin(self.sender.new_receiver()),
+            from_index,
+            filter: Box::new(filter),
+        })
     }
 }
 
+fn broadcast<MT: Clone>() -> (Sender<SSEvent<MT>>, InactiveReceiver<SSEvent<MT>>) {
+    let (mut sender, receiver) = async_broadcast::broadcast(WATCHER_CAPACITY);
+    sender.set_overflow(true);
+    (sender, receiver.deactivate())
+}
+
+#[derive(Serialize)]
+struct AppliedEvent<'a, MT, AR> {
+    index: Index,
+    message: &'a MT,
+    result: &'a AR,
+}
+
 #[derive(Debug, Clone)]
-pub enum SSEvent {
-    State(String),
-    Log(String),
+pub struct SSEvent<MT> {
+    index: Index,
+    id: String,
+    message: MT,
+    data: String,
+}
+
+impl<MT> SSEvent<MT> {
+    pub fn index(&self) -> Index {
+        self.index
+    }
+
+    pub fn message(&self) -> &MT {
+        &self.message
+    }
 }
 
-impl Event for SSEvent {
+impl<MT> Event for SSEvent<MT> {
     fn name(&self) -> &str {
-        match self {
-            SSEvent::State(_) => "state".into(),
-            SSEvent::Log(_) => "log".into(),
-        }
+        "applied"
     }
 
     fn data(&self) -> &[u8] {
-        match self {
-            SSEvent::State(s) => s.as_bytes(),
-            SSEvent::Log(l) => l.as_bytes()
-        }
+        self.data.as_bytes()
     }
 
     fn id(&self) -> Option<&str> {
-        None
+        Some(&self.id)
+    }
+}
+
+/// The events for one watcher. It ends if the watcher falls too far
+/// behind, so a client can reconnect from the last index it saw.
+pub struct Watcher<MT> {
+    history: VecDeque<SSEvent<MT>>,
+    receiver: Pin<Box<Receiver<SSEvent<MT>>>>,
+    from_index: Index,
+    filter: Box<dyn Fn(&MT) -> bool + Send + Sync>,
+}
+
+// nothing in a watcher is pinned in place; the receiver is boxed
+impl<MT> Unpin for Watcher<MT> {}
+
+impl<MT> Debug for Watcher<MT> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watcher")
+            .field("from_index", &self.from_index)
+            .field("backlog", &self.history.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<MT: Clone> Stream for Watcher<MT> {
+    type Item = SSEvent<MT>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let event = match self.history.pop_front() {
+                Some(event) => event,
+                None => match ready!(self.receiver.as_mut().poll_recv(cx)) {
+                    Some(Ok(event)) => event,
+                    Some(Err(RecvError::Overflowed(missed))) => {
+                        log::debug!("disconnecting a watcher that missed {missed} events");
+                        return Poll::Ready(None);
+                    }
+                    Some(Err(RecvError::Closed)) | None => return Poll::Ready(None),
+                },
+            };
+
+            if event.index >= self.from_index && (self.filter)(&event.message) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::{future::block_on, StreamExt};
+
+    fn send(channel: &mut SSEChannel<String>, index: Index) {
+        channel.send(index, &format!("message {index}"), &());
+    }
+
+    #[test]
+    fn watchers_end_when_a_snapshot_replaces_the_history() {
+        let mut channel = SSEChannel::new(10);
+        send(&mut channel, 1);
+        let watcher = channel.watch(1, |_| true).unwrap();
+        send(&mut channel, 2);
+
+        channel.forget_through(5);
+        send(&mut channel, 6);
+
+        let seen: Vec<_> = block_on(watcher.map(|event| event.index()).collect());
+        assert_eq!(seen, [1, 2]);
+
+        assert_eq!(channel.watch(3, |_| true).unwrap_err(), 6);
+        let mut watcher = channel.watch(6, |_| true).unwrap();
+        assert_eq!(block_on(watcher.next()).map(|event| event.index()), Some(6));
     }
 }
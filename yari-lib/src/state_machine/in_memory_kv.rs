@@ -7,6 +7,8 @@ pub struct InMemoryKV {
     inner: HashMap<String, String>,
 }
 
+/// `Get` and `Keys` are kept so that existing logs still decode, but
+/// reads should use [`KVQuery`], which doesn't go through the log.
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
 pub enum KVMessage {
     Set(String, String),
@@ -15,6 +17,22 @@ pub enum KVMessage {
     Keys(Option<String>),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub enum KVQuery {
+    Get(String),
+    Keys(Option<String>),
+}
+
+impl Message for KVQuery {
+    fn from_cli(input: Vec<String>) -> Result<Option<Self>> {
+        match (input.first().map(String::as_str), input.len()) {
+            (Some("get"), 2) => Ok(Some(Self::Get(input[1].clone()))),
+            (Some("keys"), 1..=2) => Ok(Some(Self::Keys(input.get(1).cloned()))),
+            _ => Ok(None),
+        }
+    }
+}
+
 impl Message for KVMessage {
     fn from_cli(input: Vec<String>) -> Result<Option<Self>> {
         let command: &str = input.first().ok_or(String::from("no command provided"))?;
@@ -33,6 +51,7 @@ impl Message for KVMessage {
 impl StateMachine for InMemoryKV {
     type MessageType = KVMessage;
     type ApplyResult = Option<String>;
+    type QueryType = KVQuery;
 
     fn apply(&mut self, m: &KVMessage) -> Self::ApplyResult {
         match m {
@@ -41,14 +60,22 @@ impl StateMachine for InMemoryKV {
                 None
             }
 
-            KVMessage::Get(k) => self.inner.get(k).cloned(),
+            KVMessage::Get(k) => self.query(&KVQuery::Get(k.clone())),
 
             KVMessage::Del(k) => {
                 self.inner.remove(k);
                 None
             }
 
-            KVMessage::Keys(Some(k)) => Some(
+            KVMessage::Keys(prefix) => self.query(&KVQuery::Keys(prefix.clone())),
+        }
+    }
+
+    fn query(&self, q: &KVQuery) -> Self::ApplyResult {
+        match q {
+            KVQuery::Get(k) => self.inner.get(k).cloned(),
+
+            KVQuery::Keys(Some(k)) => Some(
                 self.inner
                     .keys()
                     .filter(|key| key.starts_with(k))
@@ -57,9 +84,20 @@ impl StateMachine for InMemoryKV {
                     .join("\n"),
             ),
 
-            KVMessage::Keys(None) => {
-                Some(self.inner.keys().cloned().collect::<Vec<_>>().join("\n"))
-            }
+            KVQuery::Keys(None) => Some(self.inner.keys().cloned().collect::<Vec<_>>().join("\n")),
+        }
+    }
+
+    /// Watches the keys that start with `prefix`. Reads that went
+    /// through the log change nothing, so they aren't watched.
+    fn watches(prefix: &str, m: &KVMessage) -> bool {
+        match m {
+            KVMessage::Set(k, _) | KVMessage::Del(k) => k.starts_with(prefix),
+            KVMessage::Get(_) | KVMessage::Keys(_) => false,
         }
     }
+
+    fn type_tag() -> &'static str {
+        "yari::state_machine::in_memory_kv::InMemoryKV"
+    }
 }
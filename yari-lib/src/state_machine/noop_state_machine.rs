@@ -7,6 +7,11 @@ impl Message for () {}
 impl StateMachine for NoopStateMachine {
     type MessageType = ();
     type ApplyResult = ();
+    type QueryType = ();
 
     fn apply(&mut self, _m: &Self::MessageType) -> Self::ApplyResult {}
+
+    fn type_tag() -> &'static str {
+        "yari::state_machine::noop_state_machine::NoopStateMachine"
+    }
 }
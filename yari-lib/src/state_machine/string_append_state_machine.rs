@@ -17,9 +17,14 @@ impl Message for StringAppendMessage {
 impl StateMachine for StringAppendStateMachine {
     type MessageType = StringAppendMessage;
     type ApplyResult = Vec<String>;
+    type QueryType = ();
 
     fn apply(&mut self, m: &StringAppendMessage) -> Self::ApplyResult {
         self.state.push(m.0.clone());
         self.state.clone()
     }
+
+    fn type_tag() -> &'static str {
+        "yari::state_machine::string_append_state_machine::StringAppendStateMachine"
+    }
 }
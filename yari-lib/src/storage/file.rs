@@ -0,0 +1,117 @@
+use super::{HardState, SnapshotMeta, SnapshotReader, SnapshotWriter, StableStore};
+use crate::{
+    persistence::{read_statefile, sync_parent_dir, with_suffix, write_statefile},
+    Result, StatefileError,
+};
+use async_fs::{File, OpenOptions};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A stable store that keeps the hard state and snapshot metadata in a
+/// statefile. Snapshot data lives beside it in a file named for the last
+/// index the snapshot includes.
+#[derive(Debug)]
+pub struct FileStableStore {
+    path: PathBuf,
+    type_tag: &'static str,
+    saved: Statefile,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Statefile {
+    hard_state: HardState,
+    snapshot: Option<SnapshotMeta>,
+}
+
+impl FileStableStore {
+    pub async fn open(path: &Path, type_tag: &'static str) -> Result<Self> {
+        let mut store = Self {
+            path: path.to_path_buf(),
+            type_tag,
+            saved: Statefile::default(),
+        };
+
+        if let Some((payload, migrated)) = read_statefile(path, type_tag).await? {
+            store.saved = bincode::deserialize(&payload).map_err(StatefileError::Payload)?;
+            if migrated {
+                store.write().await?;
+            }
+        }
+
+        Ok(store)
+    }
+
+    async fn write(&self) -> Result<()> {
+        let payload = bincode::serialize(&self.saved)?;
+        write_statefile(&self.path, self.type_tag, &payload).await
+    }
+
+    fn snapshot_path(&self, meta: &SnapshotMeta) -> PathBuf {
+        with_suffix(
+            &self.path,
+            &format!(".snapshot.{}", meta.last_included_index),
+        )
+    }
+
+    fn pending_snapshot_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".snapshot.tmp")
+    }
+}
+
+#[async_trait]
+impl StableStore for FileStableStore {
+    async fn hard_state(&self) -> Result<HardState> {
+        Ok(self.saved.hard_state.clone())
+    }
+
+    async fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        if *hard_state != self.saved.hard_state {
+            self.saved.hard_state = hard_state.clone();
+            self.write().await?;
+        }
+        Ok(())
+    }
+
+    fn snapshot_meta(&self) -> Option<&SnapshotMeta> {
+        self.saved.snapshot.as_ref()
+    }
+
+    async fn snapshot_reader(&self) -> Result<Option<SnapshotReader>> {
+        match &self.saved.snapshot {
+            Some(meta) => Ok(Some(Box::new(File::open(self.snapshot_path(meta)).await?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn snapshot_writer(&mut self) -> Result<SnapshotWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.pending_snapshot_path())
+            .await?;
+        Ok(Box::new(file))
+    }
+
+    async fn finish_snapshot(&mut self, meta: SnapshotMeta) -> Result<()> {
+        // the data must be in place before the statefile refers to it
+        let pending_path = self.pending_snapshot_path();
+        let snapshot_path = self.snapshot_path(&meta);
+        File::open(&pending_path).await?.sync_all().await?;
+        async_fs::rename(&pending_path, &snapshot_path).await?;
+        sync_parent_dir(&snapshot_path).await?;
+
+        let previous = self.saved.snapshot.replace(meta);
+        self.write().await?;
+
+        if let Some(previous) = previous {
+            let previous_path = self.snapshot_path(&previous);
+            if previous_path != snapshot_path {
+                async_fs::remove_file(previous_path).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
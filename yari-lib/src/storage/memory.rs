@@ -0,0 +1,151 @@
+use super::{HardState, LogStore, SnapshotMeta, SnapshotReader, SnapshotWriter, StableStore};
+use crate::{Index, LogEntry, Message, Result, Term};
+use async_trait::async_trait;
+use futures_lite::{io::Cursor, AsyncWrite};
+use std::{
+    io,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// A log store that keeps every entry in a `Vec`. Nothing survives a
+/// restart.
+#[derive(Debug)]
+pub struct MemoryLogStore<MessageType> {
+    first_index: Index,
+    entries: Vec<LogEntry<MessageType>>,
+}
+
+impl<MessageType> Default for MemoryLogStore<MessageType> {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl<MessageType> MemoryLogStore<MessageType> {
+    pub fn new(entries: Vec<LogEntry<MessageType>>) -> Self {
+        Self {
+            first_index: entries.first().map_or(1, |entry| entry.index),
+            entries,
+        }
+    }
+
+    pub fn entries(&self) -> &[LogEntry<MessageType>] {
+        &self.entries
+    }
+
+    fn position(&self, index: Index) -> usize {
+        index
+            .saturating_sub(self.first_index)
+            .min(self.entries.len())
+    }
+}
+
+#[async_trait]
+impl<MessageType: Message + 'static> LogStore<MessageType> for MemoryLogStore<MessageType> {
+    async fn append(&mut self, entries: Vec<LogEntry<MessageType>>) -> Result<()> {
+        if let (true, Some(first)) = (self.entries.is_empty(), entries.first()) {
+            self.first_index = first.index;
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    async fn truncate(&mut self, index: Index) -> Result<()> {
+        self.entries.truncate(self.position(index));
+        Ok(())
+    }
+
+    async fn get_range(&self, range: Range<Index>) -> Result<Vec<LogEntry<MessageType>>> {
+        let start = self.position(range.start);
+        let end = self.position(range.end).max(start);
+        Ok(self.entries[start..end].to_vec())
+    }
+
+    fn term(&self, index: Index) -> Option<Term> {
+        index
+            .checked_sub(self.first_index)
+            .and_then(|i| self.entries.get(i))
+            .map(|entry| entry.term)
+    }
+
+    fn last_index(&self) -> Option<Index> {
+        self.entries.last().map(|entry| entry.index)
+    }
+
+    async fn compact(&mut self, index: Index) -> Result<()> {
+        if index >= self.first_index {
+            self.entries.drain(..self.position(index + 1));
+            self.first_index = index + 1;
+        }
+        Ok(())
+    }
+}
+
+/// A stable store that keeps the hard state and snapshot in memory.
+/// Nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct MemoryStableStore {
+    hard_state: HardState,
+    snapshot: Option<(SnapshotMeta, Vec<u8>)>,
+    pending_snapshot: SharedBuffer,
+}
+
+#[async_trait]
+impl StableStore for MemoryStableStore {
+    async fn hard_state(&self) -> Result<HardState> {
+        Ok(self.hard_state.clone())
+    }
+
+    async fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        self.hard_state = hard_state.clone();
+        Ok(())
+    }
+
+    fn snapshot_meta(&self) -> Option<&SnapshotMeta> {
+        self.snapshot.as_ref().map(|(meta, _)| meta)
+    }
+
+    async fn snapshot_reader(&self) -> Result<Option<SnapshotReader>> {
+        Ok(self
+            .snapshot
+            .as_ref()
+            .map(|(_, data)| Box::new(Cursor::new(data.clone())) as SnapshotReader))
+    }
+
+    async fn snapshot_writer(&mut self) -> Result<SnapshotWriter> {
+        self.pending_snapshot = SharedBuffer::default();
+        Ok(Box::new(self.pending_snapshot.clone()))
+    }
+
+    async fn finish_snapshot(&mut self, meta: SnapshotMeta) -> Result<()> {
+        let data = std::mem::take(&mut *self.pending_snapshot.0.lock().unwrap());
+        self.snapshot = Some((meta, data));
+        Ok(())
+    }
+}
+
+/// A writer whose bytes stay readable by the store that handed it out.
+#[derive(Debug, Default, Clone)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl AsyncWrite for SharedBuffer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,83 @@
+mod file;
+mod memory;
+mod wal;
+
+use crate::{raft::ServerConfigChange, Index, LogEntry, Message, Result, Term};
+use async_trait::async_trait;
+pub use file::FileStableStore;
+use futures_lite::{AsyncRead, AsyncWrite};
+pub use memory::{MemoryLogStore, MemoryStableStore};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Debug, ops::Range};
+pub use wal::{FileLogStore, DEFAULT_MAX_SEGMENT_SIZE};
+
+/// The state that must be durable before a server responds to any rpc.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct HardState {
+    pub current_term: Term,
+    pub voted_for: Option<String>,
+}
+
+/// Describes the log prefix that a snapshot replaces.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotMeta {
+    pub last_included_index: Index,
+    pub last_included_term: Term,
+    /// The latest server configuration at or before `last_included_index`
+    pub config: Option<ServerConfigChange>,
+    /// The client sessions as of `last_included_index`, encoded by the
+    /// raft state.
+    pub sessions: Vec<u8>,
+}
+
+pub type SnapshotWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+pub type SnapshotReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+
+/// Backing storage for the replicated log.
+///
+/// Writes are durable once the returned future resolves. Terms are
+/// expected to be cheap to look up, since they are consulted on every
+/// append; entry contents are only read through `get_range`.
+#[async_trait]
+pub trait LogStore<MessageType: Message>: Debug + Send + Sync + 'static {
+    /// Appends entries to the end of the log. The first entry's index is
+    /// always one past `last_index`.
+    async fn append(&mut self, entries: Vec<LogEntry<MessageType>>) -> Result<()>;
+
+    /// Removes the entry at `index` and every entry after it.
+    async fn truncate(&mut self, index: Index) -> Result<()>;
+
+    /// Returns the entries in `range` that are present in the store.
+    async fn get_range(&self, range: Range<Index>) -> Result<Vec<LogEntry<MessageType>>>;
+
+    fn term(&self, index: Index) -> Option<Term>;
+
+    fn last_index(&self) -> Option<Index>;
+
+    /// Discards the entry at `index` and every entry before it. If that
+    /// leaves the store empty, the next appended entry may have any index.
+    async fn compact(&mut self, index: Index) -> Result<()>;
+}
+
+/// Backing storage for the current term and vote, and for the most
+/// recent snapshot.
+#[async_trait]
+pub trait StableStore: Debug + Send + Sync + 'static {
+    async fn hard_state(&self) -> Result<HardState>;
+
+    async fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()>;
+
+    fn snapshot_meta(&self) -> Option<&SnapshotMeta>;
+
+    /// Reads the data of the current snapshot, if there is one.
+    async fn snapshot_reader(&self) -> Result<Option<SnapshotReader>>;
+
+    /// Starts a new snapshot, discarding any that was started but not
+    /// finished. The current snapshot is unaffected until
+    /// `finish_snapshot` is called.
+    async fn snapshot_writer(&mut self) -> Result<SnapshotWriter>;
+
+    /// Durably replaces the current snapshot with the one most recently
+    /// written through `snapshot_writer`.
+    async fn finish_snapshot(&mut self, meta: SnapshotMeta) -> Result<()>;
+}
@@ -0,0 +1,548 @@
+use super::LogStore;
+use crate::{
+    persistence::{statefile, sync_dir, sync_parent_dir},
+    Index, LogEntry, Message, Result, StatefileError, Term,
+};
+use async_fs::{File, OpenOptions};
+use async_trait::async_trait;
+use futures_lite::{io::SeekFrom, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, StreamExt};
+use serde::Deserialize;
+use std::{
+    marker::PhantomData,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Each record is a little-endian u32 payload length, a little-endian
+/// u32 crc32 of the payload, and the bincode-encoded entry.
+const RECORD_HEADER_LEN: usize = 8;
+
+const SEGMENT_EXTENSION: &str = "segment";
+
+/// A log store that appends entries to a directory of rolling segment
+/// files.
+///
+/// Each segment is named for the index of its first entry. Only terms and
+/// file offsets are kept in memory; entries are read back from disk when
+/// requested. A record left half-written at the end of the last segment
+/// by a crash is discarded when the log is opened.
+#[derive(Debug)]
+pub struct FileLogStore<MessageType> {
+    dir: PathBuf,
+    max_segment_size: u64,
+    segments: Vec<Segment>,
+    first_index: Index,
+    locations: Vec<Location>,
+    message_type: PhantomData<MessageType>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    first_index: Index,
+    path: PathBuf,
+    len: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    term: Term,
+    offset: u64,
+    len: usize,
+}
+
+/// The leading fields of a bincode-encoded [`LogEntry`], which can be
+/// decoded without knowing the message type.
+#[derive(Deserialize)]
+struct EntryPrefix {
+    index: Index,
+    term: Term,
+}
+
+impl<MessageType: Message + 'static> FileLogStore<MessageType> {
+    pub async fn open(path: &Path, type_tag: &'static str) -> Result<Self> {
+        import_single_file_log::<MessageType>(path, type_tag).await?;
+        async_fs::create_dir_all(path).await?;
+
+        let mut segment_paths = vec![];
+        let mut dir_entries = async_fs::read_dir(path).await?;
+        while let Some(dir_entry) = dir_entries.try_next().await? {
+            let path = dir_entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+
+            let first_index = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<Index>().ok())
+                .ok_or_else(|| StatefileError::CorruptSegment(path.clone()))?;
+            segment_paths.push((first_index, path));
+        }
+        segment_paths.sort();
+
+        let mut store = Self::empty(path);
+        if let Some((first_index, _)) = segment_paths.first() {
+            store.first_index = *first_index;
+        }
+
+        let segment_count = segment_paths.len();
+        for (n, (first_index, path)) in segment_paths.into_iter().enumerate() {
+            store
+                .recover_segment(first_index, path, n + 1 == segment_count)
+                .await?;
+        }
+
+        Ok(store)
+    }
+
+    fn empty(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            segments: vec![],
+            first_index: 1,
+            locations: vec![],
+            message_type: PhantomData,
+        }
+    }
+
+    /// Segments are rolled once they reach this size.
+    pub fn with_max_segment_size(mut self, max_segment_size: u64) -> Self {
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    async fn recover_segment(
+        &mut self,
+        first_index: Index,
+        path: PathBuf,
+        is_last: bool,
+    ) -> Result<()> {
+        if first_index != self.next_index() {
+            return Err(StatefileError::CorruptSegment(path).into());
+        }
+
+        let bytes = async_fs::read(&path).await?;
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let prefix = decode_record(&bytes[offset..])
+                .and_then(|payload| bincode::deserialize::<EntryPrefix>(payload).ok());
+
+            match prefix {
+                Some(EntryPrefix { index, term }) if index == self.next_index() => {
+                    let len = record_len(&bytes[offset..]);
+                    self.locations.push(Location {
+                        term,
+                        offset: offset as u64,
+                        len,
+                    });
+                    offset += len;
+                }
+
+                _ if is_last => {
+                    log::warn!(
+                        "discarding torn record at byte {offset} of {}",
+                        path.display()
+                    );
+                    truncate_file(&path, offset as u64).await?;
+                    break;
+                }
+
+                _ => return Err(StatefileError::CorruptSegment(path).into()),
+            }
+        }
+
+        if offset == 0 && is_last {
+            async_fs::remove_file(&path).await?;
+            sync_parent_dir(&path).await?;
+        } else {
+            self.segments.push(Segment {
+                first_index,
+                path,
+                len: offset as u64,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn next_index(&self) -> Index {
+        self.first_index + self.locations.len()
+    }
+
+    fn location(&self, index: Index) -> Option<&Location> {
+        index
+            .checked_sub(self.first_index)
+            .and_then(|position| self.locations.get(position))
+    }
+
+    fn segment_position(&self, index: Index) -> usize {
+        self.segments
+            .partition_point(|segment| segment.first_index <= index)
+            .saturating_sub(1)
+    }
+
+    fn segment_path(&self, first_index: Index) -> PathBuf {
+        self.dir
+            .join(format!("{first_index:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    async fn write_to_last_segment(
+        &mut self,
+        buf: &mut Vec<u8>,
+        pending: &mut Vec<Location>,
+    ) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let segment = self.segments.last_mut().unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&segment.path)
+            .await?;
+        let written = async {
+            file.write_all(buf).await?;
+            file.sync_data().await
+        }
+        .await;
+
+        if let Err(e) = written {
+            // whatever part of the write landed would sit between the
+            // last indexed record and the next append
+            drop(file);
+            if let Err(truncate_error) = truncate_file(&segment.path, segment.len).await {
+                log::error!(
+                    "could not discard a failed write to {}: {truncate_error}",
+                    segment.path.display()
+                );
+            }
+            return Err(e.into());
+        }
+
+        if segment.len == 0 {
+            sync_parent_dir(&segment.path).await?;
+        }
+
+        segment.len += buf.len() as u64;
+        self.locations.append(pending);
+        buf.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<MessageType: Message + 'static> LogStore<MessageType> for FileLogStore<MessageType> {
+    async fn append(&mut self, entries: Vec<LogEntry<MessageType>>) -> Result<()> {
+        let mut buf = vec![];
+        let mut pending = vec![];
+
+        if let (true, Some(first)) = (self.locations.is_empty(), entries.first()) {
+            self.first_index = first.index;
+        }
+
+        for entry in entries {
+            let payload = bincode::serialize(&entry)?;
+
+            let last_segment_is_full = self.segments.last().map_or(true, |segment| {
+                segment.len + buf.len() as u64 >= self.max_segment_size
+            });
+
+            if last_segment_is_full {
+                self.write_to_last_segment(&mut buf, &mut pending).await?;
+                self.segments.push(Segment {
+                    first_index: entry.index,
+                    path: self.segment_path(entry.index),
+                    len: 0,
+                });
+            }
+
+            pending.push(Location {
+                term: entry.term,
+                offset: self.segments.last().unwrap().len + buf.len() as u64,
+                len: RECORD_HEADER_LEN + payload.len(),
+            });
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+
+        self.write_to_last_segment(&mut buf, &mut pending).await
+    }
+
+    async fn truncate(&mut self, index: Index) -> Result<()> {
+        let index = index.max(self.first_index);
+        let Some(location) = self.location(index).copied() else {
+            return Ok(());
+        };
+
+        let position = self.segment_position(index);
+        for segment in self.segments.drain(position + 1..) {
+            async_fs::remove_file(&segment.path).await?;
+        }
+
+        let segment = self.segments.last_mut().unwrap();
+        if segment.first_index == index {
+            async_fs::remove_file(&segment.path).await?;
+            self.segments.pop();
+        } else {
+            truncate_file(&segment.path, location.offset).await?;
+            segment.len = location.offset;
+        }
+
+        sync_dir(&self.dir).await?;
+        self.locations.truncate(index - self.first_index);
+        Ok(())
+    }
+
+    async fn get_range(&self, range: Range<Index>) -> Result<Vec<LogEntry<MessageType>>> {
+        let start = range.start.max(self.first_index);
+        let end = range.end.min(self.next_index());
+        let mut entries = Vec::with_capacity(end.saturating_sub(start));
+
+        let mut index = start;
+        while index < end {
+            let position = self.segment_position(index);
+            let segment = &self.segments[position];
+            let chunk_end = self
+                .segments
+                .get(position + 1)
+                .map_or(end, |next| next.first_index.min(end));
+
+            let first = self.location(index).unwrap();
+            let last = self.location(chunk_end - 1).unwrap();
+            let mut bytes = vec![0; (last.offset - first.offset) as usize + last.len];
+
+            let mut file = File::open(&segment.path).await?;
+            file.seek(SeekFrom::Start(first.offset)).await?;
+            file.read_exact(&mut bytes).await?;
+
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let entry = decode_record(&bytes[offset..])
+                    .and_then(|payload| bincode::deserialize(payload).ok())
+                    .ok_or_else(|| StatefileError::CorruptSegment(segment.path.clone()))?;
+                entries.push(entry);
+                offset += record_len(&bytes[offset..]);
+            }
+
+            index = chunk_end;
+        }
+
+        Ok(entries)
+    }
+
+    fn term(&self, index: Index) -> Option<Term> {
+        self.location(index).map(|location| location.term)
+    }
+
+    fn last_index(&self) -> Option<Index> {
+        self.next_index()
+            .checked_sub(1)
+            .filter(|_| !self.locations.is_empty())
+    }
+
+    /// Only whole segments are removed from disk. Entries at the start of
+    /// the segment containing `index + 1` are forgotten in memory, but
+    /// are read again if the log is reopened.
+    async fn compact(&mut self, index: Index) -> Result<()> {
+        if index < self.first_index {
+            return Ok(());
+        }
+
+        let removed = if index + 1 >= self.next_index() {
+            self.locations.clear();
+            self.segments.drain(..)
+        } else {
+            self.locations.drain(..index + 1 - self.first_index);
+            let position = self.segment_position(index + 1);
+            self.segments.drain(..position)
+        };
+
+        let removed: Vec<_> = removed.collect();
+        for segment in &removed {
+            async_fs::remove_file(&segment.path).await?;
+        }
+        if !removed.is_empty() {
+            sync_dir(&self.dir).await?;
+        }
+
+        self.first_index = index + 1;
+        Ok(())
+    }
+}
+
+async fn truncate_file(path: &Path, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path).await?;
+    file.set_len(len).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+fn record_len(bytes: &[u8]) -> usize {
+    RECORD_HEADER_LEN + u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize
+}
+
+/// Returns the payload of the record at the start of `bytes`, or `None`
+/// if it is incomplete or fails its checksum.
+fn decode_record(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..RECORD_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    (crc32fast::hash(payload) == checksum).then_some(payload)
+}
+
+/// Before logs were segmented, they were written as a single statefile
+/// at the same path. This moves such a log into segments.
+async fn import_single_file_log<MessageType: Message + 'static>(
+    path: &Path,
+    type_tag: &'static str,
+) -> Result<()> {
+    let import_path = path.with_extension("log.import");
+
+    if path.is_file() {
+        let payload = statefile::decode_log(type_tag, &async_fs::read(path).await?)?;
+        let entries: Vec<LogEntry<MessageType>> =
+            bincode::deserialize(&payload).map_err(StatefileError::Payload)?;
+
+        if import_path.exists() {
+            async_fs::remove_dir_all(&import_path).await?;
+        }
+
+        log::info!("importing {} into segments", path.display());
+        async_fs::create_dir_all(&import_path).await?;
+        let mut store = FileLogStore::<MessageType>::empty(&import_path);
+        store.append(entries).await?;
+
+        async_fs::remove_file(path).await?;
+        sync_parent_dir(path).await?;
+    }
+
+    if import_path.is_dir() && !path.exists() {
+        async_fs::rename(&import_path, path).await?;
+        sync_parent_dir(path).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use futures_lite::future::block_on;
+    use std::fs;
+
+    /// An empty directory of its own for a log.
+    fn temp_log(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yari-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("8000.log")
+    }
+
+    fn entries(indexes: Range<Index>) -> Vec<LogEntry<()>> {
+        indexes
+            .map(|index| LogEntry {
+                index,
+                term: 1,
+                message: (),
+            })
+            .collect()
+    }
+
+    fn segments(path: &Path) -> Vec<PathBuf> {
+        let mut segments: Vec<_> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    #[test]
+    fn discards_a_torn_record_at_the_end_of_the_last_segment() {
+        let path = temp_log("torn");
+        block_on(async {
+            let mut store = FileLogStore::open(&path, "test").await.unwrap();
+            store.append(entries(1..4)).await.unwrap();
+
+            let segment = segments(&path).pop().unwrap();
+            let len = fs::metadata(&segment).unwrap().len();
+            let mut torn = fs::read(&segment).unwrap();
+            torn.extend_from_slice(&100u32.to_le_bytes());
+            torn.extend_from_slice(&[0; 6]);
+            fs::write(&segment, torn).unwrap();
+
+            let mut store = FileLogStore::<()>::open(&path, "test").await.unwrap();
+            assert_eq!(store.last_index(), Some(3));
+            assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+
+            store.append(entries(4..5)).await.unwrap();
+            let store = FileLogStore::<()>::open(&path, "test").await.unwrap();
+            let indexes: Vec<_> = store
+                .get_range(1..5)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.index)
+                .collect();
+            assert_eq!(indexes, [1, 2, 3, 4]);
+        });
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_checksum_mismatch_before_the_last_segment() {
+        let path = temp_log("checksum");
+        block_on(async {
+            let mut store = FileLogStore::open(&path, "test")
+                .await
+                .unwrap()
+                .with_max_segment_size(1);
+            store.append(entries(1..4)).await.unwrap();
+
+            let first = segments(&path).remove(0);
+            let mut bytes = fs::read(&first).unwrap();
+            *bytes.last_mut().unwrap() ^= 0xff;
+            fs::write(&first, bytes).unwrap();
+
+            match FileLogStore::<()>::open(&path, "test").await {
+                Err(Error::Statefile(StatefileError::CorruptSegment(corrupt))) => {
+                    assert_eq!(corrupt, first)
+                }
+                other => panic!("expected a corrupt segment, got {other:?}"),
+            }
+        });
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn imports_a_single_file_log() {
+        let path = temp_log("import");
+        // logs were first written stamped with statefile version 2
+        let mut bytes =
+            statefile::encode_log("test", &bincode::serialize(&entries(1..3)).unwrap()).unwrap();
+        bytes[statefile::MAGIC.len()..statefile::MAGIC.len() + 4]
+            .copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        block_on(async {
+            let store = FileLogStore::<()>::open(&path, "test").await.unwrap();
+            let indexes: Vec<_> = store
+                .get_range(1..3)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.index)
+                .collect();
+            assert_eq!(indexes, [1, 2]);
+        });
+        assert!(path.is_dir());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}